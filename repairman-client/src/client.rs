@@ -1,13 +1,15 @@
 use std::{
+    collections::{HashMap, HashSet},
     fs::{self, File},
     io::{self, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
 };
 
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt}, net::*, sync::mpsc, task
+    io::{AsyncRead, AsyncWrite}, net::*, sync::mpsc, task
 };
 
 
@@ -18,99 +20,304 @@ use flate2::write::DeflateDecoder;
 
 use repairman_common::*;
 
+use crate::mirror::*;
+use crate::report::*;
+use crate::resume::*;
+use crate::tls::TlsOptions;
+
+
+type ProgressCallback = Box<dyn Fn(&Progress<'_>) + Send + Sync>;
+
+const MAX_RESERVED_ENTRIES: u64 = 1 << 20;
+
+/// Checks a local directory against the manifest of a server and repairs every file that doesn't match.
+pub struct RepairSession {
+    server: String,
+    origin_path: PathBuf,
+    retries: u32,
+    verify_only: bool,
+    frame_checksum: bool,
+    tls: Option<TlsOptions>,
+    manifest_key: Option<ManifestVerifier>,
+    repository: String,
+    tag: String,
+    mirror: Option<MirrorOptions>,
+    progress: Option<ProgressCallback>,
+}
 
-pub async fn start_communication(server: &str, origin_path: &str) -> std::io::Result<()> {
-    
-    let mut stream = tokio::net::TcpStream::connect(format!("{server}:6767")).await?;
-    let mut file_list = Vec::new();
+impl RepairSession {
+    /// `server` is a `host:port` address.
+    pub fn new(server: &str, origin_path: &Path) -> RepairSession {
+        RepairSession {
+            server: server.to_string(),
+            origin_path: origin_path.to_path_buf(),
+            retries: 3,
+            verify_only: false,
+            frame_checksum: false,
+            tls: None,
+            manifest_key: None,
+            repository: String::new(),
+            tag: String::new(),
+            mirror: None,
+            progress: None,
+        }
+    }
 
-    request_hashes(&mut stream).await?;
+    /// How often the downloads are attempted again while files are still incorrect, the first attempt always runs.
+    pub fn retries(mut self, retries: u32) -> RepairSession {
+        self.retries = retries;
+        self
+    }
 
-    let response = async_parse_request(&mut stream).await?;
+    /// Only check the files, don't download anything.
+    pub fn verify_only(mut self, verify_only: bool) -> RepairSession {
+        self.verify_only = verify_only;
+        self
+    }
 
-    if response.get_type() != &RequestType::GiveHashes {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "Response isn't file hashes."));
+    /// Ask for a CRC32 on every frame, only used with protocol 0.3.
+    pub fn frame_checksum(mut self, frame_checksum: bool) -> RepairSession {
+        self.frame_checksum = frame_checksum;
+        self
     }
 
-    let mut body = vec![0u8; *response.get_body_size()];
+    pub fn tls(mut self, tls: TlsOptions) -> RepairSession {
+        self.tls = Some(tls);
+        self
+    }
 
-    stream.read_exact(&mut body).await?;
+    /// Refuse to touch any file unless the manifest is signed with this key.
+    pub fn manifest_key(mut self, manifest_key: ManifestVerifier) -> RepairSession {
+        self.manifest_key = Some(manifest_key);
+        self
+    }
 
-    let body = match str::from_utf8(&body) {
-        Ok(b) => b,
-        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Couldn't turn response body into string.")),
-    };
+    /// Named repository to repair from, servers with a single repository also answer without one.
+    pub fn repository(mut self, repository: &str) -> RepairSession {
+        self.repository = repository.to_string();
+        self
+    }
 
-    let lines = body.lines();
+    /// Release to repair to, by default and with "latest" the server's live files.
+    pub fn tag(mut self, tag: &str) -> RepairSession {
+        self.tag = tag.to_string();
+        self
+    }
 
-    for line in lines {
-        let mut part = line.split(' ');
-                
-        let path = match part.next() {
-            Some(p) => p,
-            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Responses body contains invalid path.")),
-        };
+    /// Also remove the local files that aren't in the manifest, with `verify_only` they're only reported.
+    pub fn mirror(mut self, mirror: MirrorOptions) -> RepairSession {
+        self.mirror = Some(mirror);
+        self
+    }
 
-        let hash = match part.next() {
-            Some(h) => h,
-            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Responses body contains invalid hash.")),
-        };
+    pub fn on_progress<F: Fn(&Progress<'_>) + Send + Sync + 'static>(mut self, callback: F) -> RepairSession {
+        self.progress = Some(Box::new(callback));
+        self
+    }
 
-        file_list.push(HashedFile::new(path, hash));
+    pub async fn run(&self) -> io::Result<RepairReport> {
+        match self.connect(true).await {
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+                self.emit(Progress::LegacyFallback);
+                self.connect(false).await
+            },
+            result => result,
+        }
     }
 
-    let mut loop_iter = 0;
+    fn emit(&self, progress: Progress<'_>) {
+        if let Some(ref callback) = self.progress {
+            callback(&progress);
+        }
+    }
 
-    loop {
-        let checked_files = match check_files(Path::new(origin_path), &file_list) {
-            Some(v) => v,
-            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Error checking the files against hashes.")),
+    async fn connect(&self, handshake: bool) -> io::Result<RepairReport> {
+        let stream = TcpStream::connect(&self.server).await?;
+
+        self.emit(Progress::Connected(stream.peer_addr()?));
+
+        match self.tls {
+            Some(ref tls) => {
+                let stream = tls.connect(stream).await?;
+
+                self.emit(Progress::TlsEstablished);
+
+                self.communicate(stream, handshake).await
+            },
+            None => self.communicate(stream, handshake).await,
+        }
+    }
+
+    async fn communicate<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S, handshake: bool) -> io::Result<RepairReport> {
+        let origin_path = self.origin_path.as_path();
+        let mut connection = Connection::new(stream);
+
+        // Servers without the handshake only know the original 0.1 requests.
+        let session = if handshake {
+            negotiate(&mut connection, self.frame_checksum).await?
+        } else {
+            Hello::new(&[RequestVersion::ZEROpOne], &[Capability::Deflate])
         };
 
-        for file in &checked_files {
-            println!("{}  {}", file.0.get_path(), file.1);
+        self.emit(Progress::Negotiated(connection.get_version()));
+
+        let use_delta = session.has_capability(&Capability::Delta);
+        let use_resume = session.has_capability(&Capability::Resume);
+        let format = ManifestFormat::negotiated(&session);
+
+        if self.manifest_key.is_some() && !session.has_capability(&Capability::Signature) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Server doesn't sign its manifest, refusing to continue."));
         }
-        println!(" ");
-        
-        request_files(&mut stream, &checked_files).await?;
-
-        let to_download_total: Vec<&(&HashedFile, FileState)> = checked_files.par_iter()
-            .filter(|f| {
-                if f.1 != FileState::Present {
-                    return true;
-                }
-                false
-            }).collect();
-
-        if to_download_total.is_empty() {
-            break;
-        } else if loop_iter == 3 {
-            eprintln!("Still incorrect files, after third download attempt, existing.");
-            break;
+
+        // Older servers ignore the name and would answer with their only file list.
+        if !self.repository.is_empty() && !session.has_capability(&Capability::Repositories) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Server doesn't serve named repositories."));
+        }
+
+        if !self.tag.is_empty() && self.tag != "latest" && !session.has_capability(&Capability::Releases) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Server doesn't keep releases."));
+        }
+
+        // Servers without the capability would take the name or tag for part of the next request.
+        let repository: &[u8] = match session.has_capability(&Capability::Repositories) {
+            true => self.repository.as_bytes(),
+            false => b"",
+        };
+
+        let tag: &[u8] = match session.has_capability(&Capability::Releases) {
+            true => self.tag.as_bytes(),
+            false => b"",
+        };
+
+        connection.send(RequestType::GetHashes, repository, tag).await?;
+
+        let response = connection.receive().await?.into_result()?;
+
+        if response.get_type() != &RequestType::GiveHashes {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Response isn't file hashes."));
         }
 
-        if !Path::new(origin_path).exists() {
-            fs::create_dir(origin_path)?;
+        let (file_list, manifest) = if session.has_capability(&Capability::ManifestStream) {
+            receive_manifest(&mut connection, response.get_body(), format, self.manifest_key.is_some()).await?
+        } else {
+            let body = response.into_body();
+
+            let mut file_list = Vec::new();
+            parse_manifest_entries(&body, format, &mut file_list)?;
+
+            (file_list, body)
+        };
+
+        check_manifest_links(&file_list)?;
+
+        if let Some(ref verifier) = self.manifest_key {
+            verify_manifest(&mut connection, &self.repository, &self.tag, verifier, &manifest).await?;
+
+            self.emit(Progress::ManifestVerified);
         }
 
-        let (tx, mut rx) = mpsc::channel::<Body>(100);
+        self.emit(Progress::Manifest(&file_list));
 
-        let origin = origin_path.to_string();
+        let mut loop_iter = 0;
+        let mut bytes_received = 0;
 
-        let unpacker_handle = task::spawn_blocking(move || {
-            let result: io::Result<()> = (|| {
-                let mut current_decoder: Option<DeflateDecoder<fs::File>> = None;
+        let states: Vec<FileState> = loop {
+            let checked_files = match check_files(origin_path, &file_list) {
+                Some(v) => v,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Error checking the files against hashes.")),
+            };
+
+            self.emit(Progress::Checked(&checked_files));
+
+            // Directories and symlinks are made here, they have no content to transfer.
+            let to_create: Vec<&HashedFile> = checked_files.iter()
+                .filter(|f| !f.0.is_file() && f.1 != FileState::Present)
+                .map(|f| f.0)
+                .collect();
+
+            // A symlink or directory where the manifest has a file is downloaded over, never resumed or patched through.
+            let wrong_kind: HashSet<&str> = checked_files.iter()
+                .filter(|f| f.0.is_file() && f.1 == FileState::Corrupted)
+                .filter(|f| fs::symlink_metadata(origin_path.join(f.0.get_path())).is_ok_and(|local| !local.is_file()))
+                .map(|f| f.0.get_path())
+                .collect();
+
+            let to_resume_total: HashMap<String, u64> = checked_files.par_iter()
+                .filter(|f| use_resume && f.0.is_file() && f.1 == FileState::Corrupted && !wrong_kind.contains(f.0.get_path()))
+                .filter_map(|f| {
+                    resume_offset(&origin_path.join(f.0.get_path()))
+                        .map(|offset| (f.0.get_path().to_string(), offset))
+                })
+                .collect();
+
+            let to_patch_total: Vec<&HashedFile> = checked_files.par_iter()
+                .filter(|f| use_delta && f.0.is_file() && f.1 == FileState::Corrupted && !wrong_kind.contains(f.0.get_path()))
+                .filter(|f| !to_resume_total.contains_key(f.0.get_path()))
+                .map(|f| f.0)
+                .collect();
+
+            // Without delta support corrupted files are downloaded again as a whole.
+            let to_download_total: Vec<&HashedFile> = checked_files.par_iter()
+                .filter(|f| f.0.is_file())
+                .filter(|f| {
+                    f.1 == FileState::Missing
+                        || wrong_kind.contains(f.0.get_path())
+                        || (!use_delta && f.1 == FileState::Corrupted && !to_resume_total.contains_key(f.0.get_path()))
+                })
+                .map(|f| f.0)
+                .collect();
+
+            if (to_create.is_empty() && to_download_total.is_empty() && to_resume_total.is_empty() && to_patch_total.is_empty())
+                || self.verify_only
+                || loop_iter > self.retries {
+                break checked_files.iter().map(|f| f.1).collect();
+            }
+
+            if !origin_path.exists() {
+                fs::create_dir(origin_path)?;
+            }
+
+            // Sorted so a directory is made before anything inside of it.
+            let mut to_create = to_create;
+            to_create.sort_by(|a, b| a.get_path().cmp(b.get_path()));
+
+            let to_write = to_create.iter().chain(&to_download_total).chain(&to_patch_total)
+                .map(|f| f.get_path())
+                .chain(to_resume_total.keys().map(String::as_str));
+
+            make_writable(origin_path, to_write)?;
+
+            for entry in to_create {
+                create_entry(origin_path, entry)?;
+            }
+
+            request_files(&mut connection, repository, &to_download_total, format != ManifestFormat::Text).await?;
+
+            for (file, offset) in &to_resume_total {
+                self.emit(Progress::Resuming { path: file, offset: *offset });
+                request_resume(&mut connection, file, *offset).await?;
+            }
+
+            let (tx, mut rx) = mpsc::channel::<Body>(100);
+
+            let origin = self.origin_path.clone();
+
+            let unpacker_handle = task::spawn_blocking(move || -> io::Result<()> {
+                let mut current_decoder: Option<DeflateDecoder<JournaledWriter>> = None;
 
                 while let Some(body) = rx.blocking_recv() {
                     match body {
-                        Body::StartFile(name) => {
-                            let path = Path::new(&origin).join(name);
+                        Body::StartFile(name, offset) => {
+                            let path = join_relative(&origin, &name)?;
 
                             if let Some(parent) = path.parent() {
                                 fs::create_dir_all(parent)?;
                             }
 
-                            let file = File::create(path)?;
+                            let file = match offset {
+                                Some(offset) => JournaledWriter::resume(&path, offset)?,
+                                None => JournaledWriter::create(&path)?,
+                            };
                             current_decoder = Some(DeflateDecoder::new(file));
                         },
 
@@ -122,98 +329,290 @@ pub async fn start_communication(server: &str, origin_path: &str) -> std::io::Re
 
                         Body::FileDone => {
                             if let Some(decode) = current_decoder.take() {
-                                decode.finish()?;
+                                decode.finish()?.complete()?;
+                            }
+                        },
+
+                        Body::FileFailed => {
+                            if let Some(decode) = current_decoder.take() {
+                                decode.finish()?.checkpoint()?;
                             }
                         },
                     }
                 }
+
+                // The connection dropped in the middle of a file, keep what arrived for the next attempt.
+                if let Some(decode) = current_decoder.take() {
+                    decode.finish()?.checkpoint()?;
+                }
+
                 Ok(())
-            })();
+            });
+
+            // A failed send means the unpacker stopped, its error is returned below.
+            'files: for _ in 0..(to_download_total.len() + to_resume_total.len())  {
+                let response = connection.receive().await?;
+
+                if response.get_type() == &RequestType::Error {
+                    self.emit(Progress::ServerError(&ErrorResponse::from_message(&response)?));
+                    continue;
+                }
+
+                if response.get_type() != &RequestType::GiveFiles {
+                    continue;
+                }
+
+                let file_name = response.get_file_name_str()?.to_string();
+
+                if !to_resume_total.contains_key(&file_name) && !to_download_total.iter().any(|f| f.get_path() == file_name) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Server sent a file that wasn't requested: {file_name:?}")));
+                }
+
+                self.emit(Progress::FileStarted(&file_name));
+
+                let offset = to_resume_total.get(&file_name).copied();
+
+                if tx.send(Body::StartFile(file_name.clone(), offset)).await.is_err() {
+                    break 'files;
+                }
 
-            if let Err(err) = result {
-                eprintln!("Error unpacking: {err}");
+                let mut file_end = Body::FileDone;
+                let mut file_bytes = 0;
+
+                loop {
+                    let response = connection.receive().await?;
+
+                    match response.get_type() {
+                        RequestType::EndFile => break,
+                        RequestType::Chunk => {
+                            file_bytes += response.get_body().len() as u64;
+                            self.emit(Progress::Received { path: &file_name, bytes: file_bytes });
+
+                            if tx.send(Body::Content(response.into_body())).await.is_err() {
+                                break 'files;
+                            }
+                        },
+                        RequestType::Error => {
+                            self.emit(Progress::ServerError(&ErrorResponse::from_message(&response)?));
+                            file_end = Body::FileFailed;
+                            break;
+                        },
+                        _ => {
+                            file_end = Body::FileFailed;
+                            break;
+                        },
+                    }
+                }
+
+                bytes_received += file_bytes;
+
+                match file_end {
+                    Body::FileDone => self.emit(Progress::FileDone(&file_name)),
+                    _ => self.emit(Progress::FileFailed(&file_name)),
+                }
+
+                if tx.send(file_end).await.is_err() {
+                    break 'files;
+                }
             }
-        });
 
+            drop(tx);
 
-        for _ in 0..to_download_total.len()  {
-            let response = async_parse_request(&mut stream).await?;
+            unpacker_handle.await??;
 
-            if response.get_type() != &RequestType::GiveFiles {
-                continue;
+            for file in &to_patch_total {
+                self.emit(Progress::Patching(file.get_path()));
+                bytes_received += self.request_delta(&mut connection, file).await?;
             }
 
-            let mut file_name_buffer = vec![0u8; *response.get_file_name_size()];
+            loop_iter += 1;
+        };
 
-            stream.read_exact(&mut file_name_buffer).await?;
+        connection.send(RequestType::Disconnect, b"", b"").await?;
 
-            let file_name = match String::from_utf8(file_name_buffer) {
-                Ok(f) => f,
-                Err(err) => {
-                    eprintln!("Error passing a file request to the unpacking task: {}", err);
-                    continue;
-                },
-            };
+        if !self.verify_only {
+            apply_metadata(origin_path, &file_list, &states)?;
+        }
 
-            let name = Body::StartFile(file_name);
+        let extraneous = match self.mirror {
+            Some(ref mirror) if origin_path.is_dir() => {
+                let extraneous = find_extraneous(origin_path, &file_list, mirror, &|progress: Progress<'_>| self.emit(progress))?;
 
-            match tx.send(name).await {
-                Ok(_) => (),
-                Err(err) => eprintln!("Error passing a file request to the unpacking task: {}", err),
-            };
+                for path in &extraneous {
+                    self.emit(Progress::Extraneous(path));
+                }
+
+                if !self.verify_only {
+                    remove_extraneous(origin_path, &extraneous, mirror)?;
+                }
 
+                extraneous
+            },
+            _ => Vec::new(),
+        };
+
+        let files = file_list.into_iter().zip(states).collect();
+
+        Ok(RepairReport::new(connection.get_version(), files, extraneous, loop_iter, bytes_received))
+    }
+
+    /// Patches a corrupted file in place, returns the bytes received for it.
+    async fn request_delta<S: AsyncRead + AsyncWrite + Unpin>(&self, connection: &mut Connection<S>, file: &HashedFile) -> io::Result<u64> {
+        let path = self.origin_path.join(file.get_path());
+
+        let signature_path = path.clone();
+        let signatures = task::spawn_blocking(move || -> io::Result<Vec<u8>> {
+            let mut old_file = File::open(signature_path)?;
+            let signatures = compute_signatures(&mut old_file, DELTA_BLOCK_SIZE)?;
+            Ok(encode_signatures(DELTA_BLOCK_SIZE, &signatures))
+        }).await??;
+
+        connection.send(RequestType::GetDelta, file.get_path().as_bytes(), &signatures).await?;
+
+        let response = connection.receive().await?;
+
+        if response.get_type() == &RequestType::Error {
+            self.emit(Progress::ServerError(&ErrorResponse::from_message(&response)?));
+            self.emit(Progress::FileFailed(file.get_path()));
+            return Ok(0);
+        }
+
+        if response.get_type() != &RequestType::GiveDelta {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Response isn't a delta."));
+        }
+
+        if response.get_file_name() != file.get_path().as_bytes() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Delta is for a different file."));
+        }
+
+        self.emit(Progress::FileStarted(file.get_path()));
+
+        let (tx, mut rx) = mpsc::channel::<DeltaOp>(100);
+
+        let mut os_tmp_path = path.clone().into_os_string();
+        os_tmp_path.push(".delta");
+        let tmp_path = PathBuf::from(os_tmp_path);
+
+        let patcher_path = path.clone();
+        let patcher_tmp_path = tmp_path.clone();
+        let patcher_handle = task::spawn_blocking(move || -> io::Result<()> {
+            let mut patcher = DeltaPatcher::new(File::open(&patcher_path)?, File::create(&patcher_tmp_path)?, DELTA_BLOCK_SIZE);
+
+            while let Some(op) = rx.blocking_recv() {
+                patcher.apply(op)?;
+            }
 
-            loop {
-                let response = async_parse_request(&mut stream).await?;
-
-                match response.get_type() {
-                    RequestType::EndFile => break,
-                    RequestType::Chunk => {
-                        let to_read = *response.get_body_size();
-                        let mut buffer = vec![0u8; to_read];
-                        stream.read_exact(&mut buffer).await?;
-                        let to_send = Body::Content(buffer);
-                        tx.send(to_send).await.map_err(|err| {
-                            io::Error::new(io::ErrorKind::InvalidData, err.to_string())
-                        })?;
-                    },
-                    _ => {
-                        eprintln!("Didn't recieve a right response.");
+            patcher.finish()?;
+            Ok(())
+        });
+
+        let mut completed = false;
+        let mut file_bytes = 0;
+
+        loop {
+            let response = connection.receive().await?;
+
+            match response.get_type() {
+                RequestType::EndFile => {
+                    completed = true;
+                    break;
+                },
+                RequestType::Error => {
+                    self.emit(Progress::ServerError(&ErrorResponse::from_message(&response)?));
+                    break;
+                },
+                RequestType::DeltaCopy => {
+                    let index: [u8; 8] = response.get_body().try_into()
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Delta copy doesn't contain a valid block index."))?;
+                    file_bytes += 8;
+                    if tx.send(DeltaOp::Copy(u64::from_be_bytes(index))).await.is_err() {
                         break;
-                    },
-                }
+                    }
+                },
+                RequestType::Chunk => {
+                    file_bytes += response.get_body().len() as u64;
+                    if tx.send(DeltaOp::Literal(response.into_body())).await.is_err() {
+                        break;
+                    }
+                },
+                _ => break,
             }
 
-            tx.send(Body::FileDone).await.map_err(|err| {
-                io::Error::new(io::ErrorKind::InvalidData, err.to_string())
-            })?;
+            self.emit(Progress::Received { path: file.get_path(), bytes: file_bytes });
         }
 
         drop(tx);
 
-        unpacker_handle.await?;
+        let result = patcher_handle.await?;
 
-        loop_iter += 1;
-    }
-
-    let disconnect_header = create_header(RequestVersion::ZEROpOne, RequestType::Disconnect, 0, 0);
-    stream.write_all(&disconnect_header).await?;
+        if completed && result.is_ok() {
+            fs::rename(tmp_path, path)?;
+            self.emit(Progress::FileDone(file.get_path()));
+        } else {
+            if tmp_path.exists() {
+                fs::remove_file(tmp_path)?;
+            }
+            result?;
+            self.emit(Progress::FileFailed(file.get_path()));
+        }
 
-    Ok(())
+        Ok(file_bytes)
+    }
 }
 
 enum Body {
-    StartFile(String),
+    StartFile(String, Option<u64>),
     Content(Vec<u8>),
     FileDone,
+    FileFailed,
 }
 
-async fn request_hashes(stream: &mut TcpStream) -> io::Result<()> {
-    let header = create_header(RequestVersion::ZEROpOne, RequestType::GetHashes, 0, 0);
+/// Sends HELLO and switches the connection to the version the server picked.
+/// Fails with `ErrorKind::Unsupported` if the server predates the handshake.
+async fn negotiate<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<S>, frame_checksum: bool) -> io::Result<Hello> {
+    let mut capabilities = vec![Capability::Deflate, Capability::Delta, Capability::Resume, Capability::Signature, Capability::Repositories, Capability::Releases, Capability::ManifestStream, Capability::LengthPrefixed, Capability::Metadata];
+    if frame_checksum {
+        capabilities.push(Capability::Checksum);
+    }
 
-    stream.write_all(&header).await?;
+    let hello = Hello::new(&SUPPORTED_VERSIONS, &capabilities);
 
-    Ok(())
+    // HELLO always goes out with a 0.1 header, so that any server can parse it.
+    connection.send(RequestType::Hello, b"", &hello.encode()).await?;
+
+    let response = match connection.receive().await {
+        Ok(r) => r,
+        Err(err) if matches!(err.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset) => {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Server closed the connection on HELLO."));
+        },
+        Err(err) => return Err(err),
+    };
+
+    if response.get_type() == &RequestType::Error {
+        let error = ErrorResponse::from_message(&response)?;
+
+        if error.get_code() == ErrorCode::BadRequest {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, error));
+        }
+
+        return Err(io::Error::other(error));
+    }
+
+    if response.get_type() != &RequestType::Hello {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Response isn't a HELLO."));
+    }
+
+    let session = Hello::decode(response.get_body())?;
+
+    let version = match session.highest_version() {
+        Some(v) if SUPPORTED_VERSIONS.contains(&v) => v,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Server picked a protocol version that isn't supported.")),
+    };
+
+    connection.set_version(version);
+    connection.set_checksum(session.has_capability(&Capability::Checksum));
+
+    Ok(session)
 }
 
 
@@ -230,10 +629,39 @@ fn check_files<'a>(path: &Path, files: &'a [HashedFile]) -> Option<Vec<(&'a Hash
         let list2: Vec<(&HashedFile, FileState)> = files.par_iter().map(|entry| { 
             let full_path = path.join(entry.get_path());
 
+            let local = fs::symlink_metadata(&full_path).ok();
+            let kind = entry.get_metadata().map(|m| m.get_kind());
+
+            match kind {
+                Some(FileKind::Directory) => return match local {
+                    Some(local) if local.is_dir() => (entry, FileState::Present),
+                    Some(_) => (entry, FileState::Corrupted),
+                    None => (entry, FileState::Missing),
+                },
+                Some(FileKind::Symlink(target)) => return match local {
+                    Some(_) if fs::read_link(&full_path).is_ok_and(|t| t == Path::new(target)) => (entry, FileState::Present),
+                    Some(_) => (entry, FileState::Corrupted),
+                    None => (entry, FileState::Missing),
+                },
+                _ => (),
+            }
+
+            // Never hashed through, a symlink or directory in place of a file is replaced as a whole.
+            if local.as_ref().is_some_and(|local| !local.is_file()) {
+                return (entry, FileState::Corrupted);
+            }
+
             if !full_path.exists() {
                 return (entry, FileState::Missing);
             }
 
+            // A file of the wrong size can't have the right hash.
+            if let Some(metadata) = entry.get_metadata()
+                && let Some(local) = local
+                && local.len() != metadata.get_size() {
+                return (entry, FileState::Corrupted);
+            }
+
             let mut hasher = Blake2s256::new();
 
             let file_hash = match get_hash_file(&full_path, &mut hasher) {
@@ -256,24 +684,295 @@ fn check_files<'a>(path: &Path, files: &'a [HashedFile]) -> Option<Vec<(&'a Hash
     None
 }
 
-async fn request_files(stream: &mut TcpStream, checked_files: &[(&HashedFile, FileState)]) -> std::io::Result<()> {
-    let body: String = checked_files.par_iter()
-        .filter(|f| {
-            if f.1 != FileState::Present {
-                return true;
+/// Appends the entries of a GIVE-HASHES body or manifest CHUNK to `file_list`, servers without
+/// length-prefixed bodies send "path hash" lines.
+fn parse_manifest_entries(body: &[u8], format: ManifestFormat, file_list: &mut Vec<HashedFile>) -> io::Result<()> {
+    if format != ManifestFormat::Text {
+        let start = file_list.len();
+        decode_manifest(body, format == ManifestFormat::Metadata, file_list)?;
+
+        for file in &file_list[start..] {
+            check_manifest_path(file.get_path())?;
+        }
+
+        return Ok(());
+    }
+
+    let body = match str::from_utf8(body) {
+        Ok(b) => b,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Couldn't turn response body into string.")),
+    };
+
+    for line in body.lines() {
+        let mut part = line.split(' ');
+
+        let path = match part.next() {
+            Some(p) => p,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Responses body contains invalid path.")),
+        };
+
+        let hash = match part.next() {
+            Some(h) => h,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Responses body contains invalid hash.")),
+        };
+
+        check_manifest_path(path)?;
+
+        file_list.push(HashedFile::new(path, hash));
+    }
+
+    Ok(())
+}
+
+/// Symlinks from the manifest may only point inside the served root, and nothing may be listed below one,
+/// writing it would go through the link.
+fn check_manifest_links(files: &[HashedFile]) -> io::Result<()> {
+    let mut links = HashSet::new();
+
+    for file in files {
+        if let Some(FileKind::Symlink(target)) = file.get_metadata().map(|m| m.get_kind()) {
+            check_link_target(file.get_path(), target)?;
+            links.insert(file.get_path());
+        }
+    }
+
+    if links.is_empty() {
+        return Ok(());
+    }
+
+    for file in files {
+        let mut path = file.get_path();
+
+        while let Some((parent, _)) = path.rsplit_once('/') {
+            if links.contains(parent) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Manifest lists {:?} inside the symlink {parent:?}.", file.get_path())));
             }
-            false
-        })
+
+            path = parent;
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes a directory or symlink entry, whatever is in its place is removed first. A directory in the way
+/// of a symlink is only removed if it's empty.
+fn create_entry(origin_path: &Path, entry: &HashedFile) -> io::Result<()> {
+    let path = join_relative(origin_path, entry.get_path())?;
+
+    let existing = fs::symlink_metadata(&path).ok();
+
+    match entry.get_metadata().map(|m| m.get_kind()) {
+        Some(FileKind::Directory) => {
+            if existing.is_some_and(|e| !e.is_dir()) {
+                fs::remove_file(&path)?;
+            }
+
+            fs::create_dir_all(&path)
+        },
+        Some(FileKind::Symlink(target)) => {
+            match existing {
+                Some(e) if e.is_dir() => fs::remove_dir(&path)?,
+                Some(_) => fs::remove_file(&path)?,
+                None => (),
+            }
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            create_symlink(target, &path)
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &str, path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("Can't create the symlink {path:?} on this platform.")))
+}
+
+/// Lets the owner write the entries about to be repaired and the directories holding them, a read-only mode
+/// from an earlier repair would get in the way. `apply_metadata` sets the manifest's modes again afterwards.
+fn make_writable<'a, I: IntoIterator<Item = &'a str>>(origin_path: &Path, paths: I) -> io::Result<()> {
+    let mut done = HashSet::new();
+
+    for path in paths {
+        let mut current = Some(path);
+
+        // A directory that was seen already had its parents done with it.
+        while let Some(path) = current && done.insert(path) {
+            let local = join_relative(origin_path, path)?;
+
+            if let Ok(metadata) = fs::symlink_metadata(&local) && !metadata.is_symlink() {
+                make_owner_writable(&local, &metadata)?;
+            }
+
+            current = path.rsplit_once('/').map(|(parent, _)| parent);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_owner_writable(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = metadata.permissions().mode();
+    let writable = if metadata.is_dir() { mode | 0o700 } else { mode | 0o600 };
+
+    if writable != mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(writable))
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to make {path:?} writable: {e}")))?;
+    }
+
+    Ok(())
+}
+
+// Modes from the manifest are only set on unix, nothing else can have been made read-only by them.
+#[cfg(not(unix))]
+fn make_owner_writable(_path: &Path, _metadata: &fs::Metadata) -> io::Result<()> {
+    Ok(())
+}
+
+/// Sets mode and modification time from the manifest on the entries that are present. Done in reverse so
+/// a directory gets its time after everything in it, symlinks are left as they were made. The mode comes
+/// last, setting the time needs the file opened.
+fn apply_metadata(origin_path: &Path, files: &[HashedFile], states: &[FileState]) -> io::Result<()> {
+    let mut present: Vec<(&HashedFile, &FileMetadata)> = files.iter().zip(states)
+        .filter(|(_, state)| **state == FileState::Present)
+        .filter_map(|(f, _)| f.get_metadata().map(|m| (f, m)))
+        .filter(|(_, m)| !matches!(m.get_kind(), FileKind::Symlink(_)))
+        .collect();
+
+    present.sort_by(|a, b| b.0.get_path().cmp(a.0.get_path()));
+
+    for (file, metadata) in present {
+        let path = join_relative(origin_path, file.get_path())?;
+
+        if let Some(modified) = metadata.get_modified() {
+            File::open(&path)?.set_modified(UNIX_EPOCH + Duration::from_secs(modified))
+                .map_err(|e| io::Error::new(e.kind(), format!("Failed to set the modification time of {path:?}: {e}")))?;
+        }
+
+        if let Some(mode) = metadata.get_mode() {
+            set_mode(&path, mode)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Reads a streamed manifest, `count_body` is the GIVE-HASHES body with the entry count. The raw manifest
+/// is only kept with `keep_manifest`, for checking its signature.
+async fn receive_manifest<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<S>, count_body: &[u8], format: ManifestFormat, keep_manifest: bool) -> io::Result<(Vec<HashedFile>, Vec<u8>)> {
+    let count = u64::from_be_bytes(count_body.try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Manifest entry count is invalid."))?);
+
+    // The count comes from the server, it only sizes the first allocation up to a limit.
+    let mut file_list = Vec::with_capacity(count.min(MAX_RESERVED_ENTRIES) as usize);
+    let mut manifest = Vec::new();
+
+    loop {
+        let message = connection.receive().await?.into_result()?;
+
+        match message.get_type() {
+            RequestType::Chunk => {
+                parse_manifest_entries(message.get_body(), format, &mut file_list)?;
+
+                if keep_manifest {
+                    manifest.extend_from_slice(message.get_body());
+                }
+            },
+            RequestType::EndFile => break,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Response isn't part of the manifest.")),
+        }
+
+        if file_list.len() as u64 > count {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Manifest has more entries than announced."));
+        }
+    }
+
+    if file_list.len() as u64 != count {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Manifest has {} entries instead of the announced {count}.", file_list.len())));
+    }
+
+    Ok((file_list, manifest))
+}
+
+/// The signature has to cover the repository and tag asked for, a manifest signed for another one is refused.
+async fn verify_manifest<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<S>, repository: &str, tag: &str, verifier: &ManifestVerifier, manifest: &[u8]) -> io::Result<()> {
+    connection.send(RequestType::GetSignature, repository.as_bytes(), b"").await?;
+
+    let response = connection.receive().await?.into_result()?;
+
+    if response.get_type() != &RequestType::GiveSignature {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Response isn't a manifest signature."));
+    }
+
+    let signature: [u8; SIGNATURE_LEN] = response.get_body().try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Server didn't sign the manifest, refusing to continue."))?;
+
+    verifier.verify(&signed_payload(repository, tag, &manifest_digest(manifest)), &signature)
+}
+
+async fn request_files<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<S>, repository: &[u8], files: &[&HashedFile], length_prefixed: bool) -> std::io::Result<()> {
+    if length_prefixed {
+        let body = encode_names(files.iter().map(|f| f.get_path()))?;
+        return connection.send(RequestType::GetFiles, repository, &body).await;
+    }
+
+    let body: String = files.par_iter()
         .map(|f| {
-            format!("{}\n", f.0.get_path())
+            format!("{}\n", f.get_path())
         })
         .collect();
 
-    let body_size = body.len() as u32;
-    let header = create_header(RequestVersion::ZEROpOne, RequestType::GetFiles, 0, body_size);
+    connection.send(RequestType::GetFiles, repository, body.as_bytes()).await
+}
 
-    stream.write_all(&header).await?;
-    stream.write_all(body.as_bytes()).await?;
+async fn request_resume<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<S>, file: &str, offset: u64) -> io::Result<()> {
+    connection.send(RequestType::GetFilesFrom, file.as_bytes(), &offset.to_be_bytes()).await
+}
 
-    Ok(())
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_in_place_of_a_file_is_corrupted() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("real"), b"content").unwrap();
+        std::os::unix::fs::symlink("real", dir.path().join("file")).unwrap();
+
+        // The hash of the link target, a symlink must not pass for the file even then.
+        let hash = format!("{:x}", Blake2s256::digest(b"content"));
+        let files = [HashedFile::new("file", &hash), HashedFile::new("real", &hash)];
+
+        let checked = check_files(dir.path(), &files).unwrap();
+
+        assert_eq!(checked[0].1, FileState::Corrupted);
+        assert_eq!(checked[1].1, FileState::Present);
+    }
+}
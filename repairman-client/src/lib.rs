@@ -0,0 +1,14 @@
+mod client;
+mod mirror;
+mod report;
+mod resume;
+#[cfg(test)]
+mod testing;
+mod tls;
+
+pub use client::*;
+pub use mirror::MirrorOptions;
+pub use report::*;
+pub use tls::TlsOptions;
+
+pub use repairman_common::{ErrorResponse, FileState, HashedFile, ManifestVerifier, RequestVersion};
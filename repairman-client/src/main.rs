@@ -1,22 +1,271 @@
-use std::env;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    process::ExitCode,
+};
 
-use client::start_communication;
+use clap::{ArgAction, Parser};
+use repairman_client::{FileState, ManifestVerifier, MirrorOptions, Progress, RepairSession, TlsOptions};
 
+/// Exit code when files are still missing or corrupted, with `--verify-only` also when anything would change.
+/// Other errors exit with 1.
+const EXIT_INCOMPLETE: u8 = 2;
 
-mod client;
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Server as host, host:port, an IPv6 address or [IPv6]:port
+    server: String,
+
+    path: String,
+
+    #[arg(short, long, default_value_t = 6767)]
+    port: u16,
+
+    /// Download attempts on top of the first one while files are still incorrect
+    #[arg(short, long, default_value_t = 3)]
+    retries: u32,
+
+    /// Only check the files against the server, don't download anything
+    #[arg(long, visible_alias = "dry-run")]
+    verify_only: bool,
+
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Ask the server to add a CRC32 to every frame, needs protocol 0.3
+    #[arg(long)]
+    frame_checksum: bool,
+
+    /// PEM file with the CA certificates to trust, enables TLS
+    #[arg(long)]
+    tls_ca: Option<String>,
+
+    /// Name to verify the server certificate against, defaults to the server host
+    #[arg(long, requires = "tls_ca")]
+    tls_name: Option<String>,
+
+    /// PEM Ed25519 public key the manifest has to be signed with
+    #[arg(long)]
+    manifest_key: Option<String>,
+
+    /// Repository to repair from, for servers that serve more than one
+    #[arg(long)]
+    repository: Option<String>,
+
+    /// Release to repair to, "latest" is the server's live files
+    #[arg(long)]
+    tag: Option<String>,
+
+    /// Delete local files that aren't in the manifest, with --verify-only they're only listed
+    #[arg(long)]
+    mirror: bool,
+
+    /// Glob of local files --mirror keeps, can be given several times
+    #[arg(long, requires = "mirror")]
+    exclude: Vec<String>,
+
+    /// Move the files --mirror would delete into this directory instead
+    #[arg(long, requires = "mirror")]
+    quarantine: Option<String>,
+}
+
+fn server_address(server: &str, default_port: u16) -> Result<String, String> {
+    if let Ok(addr) = server.parse::<SocketAddr>() {
+        return Ok(addr.to_string());
+    }
+
+    let bare = server.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(server);
+
+    if let Ok(ip) = bare.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port).to_string());
+    }
+
+    match server.rsplit_once(':') {
+        Some((host, port)) => {
+            if host.is_empty() || host.contains(':') || port.parse::<u16>().is_err() {
+                return Err(format!("Invalid server address: {server}"));
+            }
+            Ok(server.to_string())
+        },
+        None => Ok(format!("{server}:{default_port}")),
+    }
+}
+
+fn print_progress(progress: &Progress<'_>, verbosity: u8) {
+    match progress {
+        Progress::Connected(addr) if verbosity > 1 => println!("Connected to {addr}"),
+        Progress::TlsEstablished if verbosity > 1 => println!("TLS session established"),
+        Progress::LegacyFallback if verbosity > 1 => println!("Server doesn't support the handshake, reconnecting with protocol 0.1"),
+        Progress::Negotiated(version) if verbosity > 1 => println!("Using protocol {version}"),
+        Progress::Manifest(files) if verbosity > 0 => {
+            let size: u64 = files.iter().filter_map(|f| f.get_metadata()).map(|m| m.get_size()).sum();
+            println!("Manifest lists {} entries, {size} bytes", files.len());
+        },
+        Progress::ManifestVerified if verbosity > 1 => println!("Manifest signature is valid"),
+        Progress::Checked(files) if verbosity > 0 => {
+            for file in files.iter() {
+                println!("{}  {}", file.0.get_path(), file.1);
+            }
+            println!(" ");
+        },
+        Progress::Resuming { path, offset } if verbosity > 1 => println!("Resuming {path} from byte {offset}"),
+        Progress::Patching(path) if verbosity > 1 => println!("Patching {path} with a delta"),
+        Progress::FileFailed(path) if verbosity > 1 => println!("Couldn't repair {path}"),
+        Progress::ServerError(err) => eprintln!("Server error: {err}"),
+        Progress::Extraneous(path) if verbosity > 0 => println!("{path}  Extraneous"),
+        Progress::SkippedLocal(path) => eprintln!("Skipping {path:?}, its path isn't valid UTF-8"),
+        _ => (),
+    }
+}
+
+fn server_host(server_address: &str) -> &str {
+    let host = server_address.rsplit_once(':').map_or(server_address, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
 
 #[tokio::main]
-async fn main() {
-    let args: Vec<String> = env::args().collect();
+async fn main() -> ExitCode {
+    let args = Args::parse();
 
-    if args.len() > 3 {
-        eprintln!("Too many arguments passed.");
-        return;
-    } else if args.len() < 3 {
-        eprintln!("Not enough arguments passed.");
-        return;
+    let server = match server_address(&args.server, args.port) {
+        Ok(s) => s,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        },
+    };
+
+    let tls = match args.tls_ca {
+        Some(ref ca) => {
+            let host = match args.tls_name {
+                Some(ref name) => name.as_str(),
+                None => server_host(&server),
+            };
+
+            match TlsOptions::load(Path::new(ca), host) {
+                Ok(t) => Some(t),
+                Err(err) => {
+                    eprintln!("Error loading TLS configuration: {err}");
+                    return ExitCode::FAILURE;
+                },
+            }
+        },
+        None => None,
+    };
+
+    let manifest_key = match args.manifest_key {
+        Some(ref key) => match ManifestVerifier::from_pem_file(Path::new(key)) {
+            Ok(v) => Some(v),
+            Err(err) => {
+                eprintln!("Error loading manifest key: {err}");
+                return ExitCode::FAILURE;
+            },
+        },
+        None => None,
+    };
+
+    let verbosity = if args.quiet { 0 } else { args.verbose + 1 };
+
+    let mirror = if args.mirror {
+        match MirrorOptions::new(&args.exclude) {
+            Ok(m) => Some(match args.quarantine {
+                Some(ref dir) => m.quarantine(Path::new(dir)),
+                None => m,
+            }),
+            Err(err) => {
+                eprintln!("{err}");
+                return ExitCode::FAILURE;
+            },
+        }
+    } else {
+        None
+    };
+
+    let mut session = RepairSession::new(&server, Path::new(&args.path))
+        .retries(args.retries)
+        .verify_only(args.verify_only)
+        .frame_checksum(args.frame_checksum)
+        .on_progress(move |progress| print_progress(progress, verbosity));
+
+    if let Some(tls) = tls {
+        session = session.tls(tls);
+    }
+
+    if let Some(manifest_key) = manifest_key {
+        session = session.manifest_key(manifest_key);
+    }
+
+    if let Some(ref repository) = args.repository {
+        session = session.repository(repository);
+    }
+
+    if let Some(ref tag) = args.tag {
+        session = session.tag(tag);
+    }
+
+    if let Some(mirror) = mirror {
+        session = session.mirror(mirror);
+    }
+
+    match session.run().await {
+        Ok(report) => {
+            let extraneous = args.verify_only && !report.get_extraneous().is_empty();
+
+            if extraneous {
+                println!("{} extraneous files would be removed.", report.get_extraneous().len());
+            }
+
+            if report.is_complete() {
+                return if extraneous { ExitCode::from(EXIT_INCOMPLETE) } else { ExitCode::SUCCESS };
+            }
+
+            if args.verify_only {
+                println!("{} files would be repaired.", report.count(FileState::Missing) + report.count(FileState::Corrupted));
+            } else {
+                eprintln!("Still incorrect files, after {} download attempts, exiting.", report.get_attempts());
+            }
+
+            ExitCode::from(EXIT_INCOMPLETE)
+        },
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_address_adds_the_default_port() {
+        assert_eq!(server_address("example.com", 9000).unwrap(), "example.com:9000");
+        assert_eq!(server_address("example.com:1234", 9000).unwrap(), "example.com:1234");
+        assert_eq!(server_address("127.0.0.1", 9000).unwrap(), "127.0.0.1:9000");
+        assert_eq!(server_address("::1", 9000).unwrap(), "[::1]:9000");
+        assert_eq!(server_address("[::1]", 9000).unwrap(), "[::1]:9000");
+        assert_eq!(server_address("[::1]:1234", 9000).unwrap(), "[::1]:1234");
     }
 
-    let result = start_communication(&args[1], &args[2]).await;
-    result.unwrap_or_else(|err| { eprintln!("{err}") });
+    #[test]
+    fn server_address_rejects_bad_ports() {
+        assert!(server_address("example.com:", 9000).is_err());
+        assert!(server_address("[::1]:", 9000).is_err());
+        assert!(server_address(":1234", 9000).is_err());
+        assert!(server_address("example.com:http", 9000).is_err());
+        assert!(server_address("example.com:65536", 9000).is_err());
+        assert!(server_address("fe80::1::2", 9000).is_err());
+    }
+
+    #[test]
+    fn server_host_drops_port_and_brackets() {
+        for (server, host) in [("example.com", "example.com"), ("example.com:1234", "example.com"), ("::1", "::1"), ("[::1]", "::1"), ("[::1]:1234", "::1")] {
+            assert_eq!(server_host(&server_address(server, 9000).unwrap()), host, "{server}");
+        }
+    }
 }
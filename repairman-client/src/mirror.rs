@@ -0,0 +1,351 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use repairman_common::HashedFile;
+
+use crate::report::Progress;
+
+/// Sidecars the client writes next to a file while repairing it, kept as long as the file is in the manifest.
+const SIDECAR_SUFFIXES: [&str; 2] = [".resume", ".delta"];
+
+/// What mirror mode does with local files that aren't in the manifest.
+pub struct MirrorOptions {
+    excludes: GlobSet,
+    quarantine: Option<PathBuf>,
+}
+
+impl MirrorOptions {
+    /// `excludes` are glob patterns matched against paths relative to the target directory, a pattern without
+    /// a '/' matches at any depth. Matching files and directories are never touched.
+    pub fn new(excludes: &[String]) -> io::Result<MirrorOptions> {
+        let mut builder = GlobSetBuilder::new();
+
+        for pattern in excludes {
+            builder.add(exclude_glob(pattern, pattern)?);
+
+            if !pattern.contains('/') {
+                builder.add(exclude_glob(&format!("**/{pattern}"), pattern)?);
+            }
+        }
+
+        let excludes = builder.build()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+
+        Ok(MirrorOptions { excludes, quarantine: None })
+    }
+
+    /// Move extraneous files below `dir` instead of deleting them, it has to be on the same filesystem. Every run
+    /// moves them into a new subdirectory named after its start time, so nothing quarantined earlier is overwritten.
+    pub fn quarantine(mut self, dir: &Path) -> MirrorOptions {
+        self.quarantine = Some(dir.to_path_buf());
+        self
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        self.excludes.is_match(path)
+    }
+}
+
+fn exclude_glob(glob: &str, pattern: &str) -> io::Result<Glob> {
+    Glob::new(glob).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid exclude pattern {pattern:?}: {err}")))
+}
+
+/// Local paths under `root` that aren't in `files`, children before the directory holding them. Directories
+/// are only listed when nothing in them is kept. Paths that aren't valid UTF-8 are kept and reported to `emit`.
+pub fn find_extraneous(root: &Path, files: &[HashedFile], options: &MirrorOptions, emit: &dyn Fn(Progress<'_>)) -> io::Result<Vec<String>> {
+    let mut known: HashSet<&str> = HashSet::new();
+
+    for file in files {
+        let mut path = file.get_path();
+        known.insert(path);
+
+        // Servers without the metadata capability don't list directories.
+        while let Some((parent, _)) = path.rsplit_once('/') {
+            known.insert(parent);
+            path = parent;
+        }
+    }
+
+    // Moving the quarantine into itself would never end.
+    let quarantine = match options.quarantine {
+        Some(ref dir) => fs::canonicalize(dir).ok(),
+        None => None,
+    };
+
+    let mut extraneous = Vec::new();
+    walk(root, "", &known, options, quarantine.as_deref(), emit, &mut extraneous)?;
+
+    Ok(extraneous)
+}
+
+/// Returns whether everything below `dir` is extraneous.
+fn walk(dir: &Path, prefix: &str, known: &HashSet<&str>, options: &MirrorOptions, quarantine: Option<&Path>, emit: &dyn Fn(Progress<'_>), extraneous: &mut Vec<String>) -> io::Result<bool> {
+    let mut all_extraneous = true;
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            emit(Progress::SkippedLocal(&entry.path()));
+            all_extraneous = false;
+            continue;
+        };
+
+        let path = format!("{prefix}{name}");
+
+        if options.is_excluded(&path) || is_sidecar(&path, known) {
+            all_extraneous = false;
+            continue;
+        }
+
+        if quarantine.is_some() && fs::canonicalize(entry.path()).ok().as_deref() == quarantine {
+            all_extraneous = false;
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            let empty = walk(&entry.path(), &format!("{path}/"), known, options, quarantine, emit, extraneous)?;
+
+            if known.contains(path.as_str()) || !empty {
+                all_extraneous = false;
+            } else {
+                extraneous.push(path);
+            }
+        } else if known.contains(path.as_str()) {
+            all_extraneous = false;
+        } else {
+            extraneous.push(path);
+        }
+    }
+
+    Ok(all_extraneous)
+}
+
+/// A new directory below `quarantine` for this run, named after the current time with a counter if that's taken.
+fn create_run_dir(quarantine: &Path) -> io::Result<PathBuf> {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs());
+
+    fs::create_dir_all(quarantine)?;
+
+    for attempt in 0u32.. {
+        let dir = match attempt {
+            0 => quarantine.join(seconds.to_string()),
+            n => quarantine.join(format!("{seconds}-{n}")),
+        };
+
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("No free run directory in {quarantine:?}.")))
+}
+
+fn is_sidecar(path: &str, known: &HashSet<&str>) -> bool {
+    SIDECAR_SUFFIXES.iter().any(|suffix| path.strip_suffix(suffix).is_some_and(|file| known.contains(file)))
+}
+
+/// Deletes or quarantines the paths from `find_extraneous`, in the order it returned them.
+pub fn remove_extraneous(root: &Path, extraneous: &[String], options: &MirrorOptions) -> io::Result<()> {
+    let run_dir = match options.quarantine {
+        Some(ref quarantine) if !extraneous.is_empty() => Some(create_run_dir(quarantine)?),
+        _ => None,
+    };
+
+    for path in extraneous {
+        let local = root.join(path);
+        let is_dir = fs::symlink_metadata(&local)?.is_dir();
+
+        let result = match run_dir {
+            // The files in it were moved already, only the empty directory is left.
+            _ if is_dir => fs::remove_dir(&local),
+            Some(ref run_dir) => {
+                let target = run_dir.join(path);
+
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                // The run directory is new, but a rename would replace whatever is there without a word.
+                if fs::symlink_metadata(&target).is_ok() {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Quarantine already holds {target:?}.")));
+                }
+
+                fs::rename(&local, &target)
+            },
+            None => fs::remove_file(&local),
+        };
+
+        result.map_err(|err| io::Error::new(err.kind(), format!("Failed to remove {local:?}: {err}")))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    use crate::testing::TempDir;
+
+    fn write(root: &Path, path: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"x").unwrap();
+    }
+
+    fn manifest(paths: &[&str]) -> Vec<HashedFile> {
+        paths.iter().map(|path| HashedFile::new(path, "aaaa")).collect()
+    }
+
+    fn extraneous(root: &Path, files: &[HashedFile], options: &MirrorOptions) -> Vec<String> {
+        find_extraneous(root, files, options, &|_| ()).unwrap()
+    }
+
+    fn sorted(mut paths: Vec<String>) -> Vec<String> {
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn children_come_before_their_directory() {
+        let dir = TempDir::new();
+        write(dir.path(), "keep/file");
+        write(dir.path(), "keep/old");
+        write(dir.path(), "gone/a");
+        write(dir.path(), "gone/deeper/b");
+
+        let found = extraneous(dir.path(), &manifest(&["keep/file"]), &MirrorOptions::new(&[]).unwrap());
+
+        assert_eq!(sorted(found.clone()), ["gone", "gone/a", "gone/deeper", "gone/deeper/b", "keep/old"]);
+
+        let position = |path: &str| found.iter().position(|p| p == path).unwrap();
+        assert!(position("gone/deeper/b") < position("gone/deeper"));
+        assert!(position("gone/deeper") < position("gone"));
+        assert!(position("gone/a") < position("gone"));
+    }
+
+    #[test]
+    fn directory_with_a_kept_file_stays() {
+        let dir = TempDir::new();
+        write(dir.path(), "mixed/old");
+        write(dir.path(), "mixed/saves/slot1");
+
+        let options = MirrorOptions::new(&["mixed/saves/**".to_string()]).unwrap();
+
+        assert_eq!(extraneous(dir.path(), &[], &options), ["mixed/old"]);
+    }
+
+    #[test]
+    fn excludes_with_and_without_slash() {
+        let dir = TempDir::new();
+        write(dir.path(), "a.cfg");
+        write(dir.path(), "sub/b.cfg");
+        write(dir.path(), "saves/one");
+        write(dir.path(), "sub/saves/two");
+
+        let options = MirrorOptions::new(&["*.cfg".to_string(), "saves/*".to_string()]).unwrap();
+
+        // Without a '/' the pattern matches at any depth, with one only from the root.
+        assert_eq!(sorted(extraneous(dir.path(), &[], &options)), ["sub/saves", "sub/saves/two"]);
+    }
+
+    #[test]
+    fn invalid_exclude_is_refused() {
+        assert_eq!(MirrorOptions::new(&["a[".to_string()]).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn sidecars_are_kept_only_next_to_manifest_files() {
+        let dir = TempDir::new();
+        write(dir.path(), "file");
+        write(dir.path(), "file.resume");
+        write(dir.path(), "file.delta");
+        write(dir.path(), "other.resume");
+        write(dir.path(), "other.delta");
+
+        let found = extraneous(dir.path(), &manifest(&["file"]), &MirrorOptions::new(&[]).unwrap());
+
+        assert_eq!(sorted(found), ["other.delta", "other.resume"]);
+    }
+
+    #[test]
+    fn quarantine_inside_the_root_is_skipped() {
+        let dir = TempDir::new();
+        write(dir.path(), "old");
+        write(dir.path(), "quarantine/earlier/old");
+
+        let options = MirrorOptions::new(&[]).unwrap().quarantine(&dir.path().join("quarantine"));
+
+        assert_eq!(extraneous(dir.path(), &[], &options), ["old"]);
+    }
+
+    #[test]
+    fn quarantine_keeps_earlier_runs() {
+        let dir = TempDir::new();
+        let quarantine = dir.path().join("quarantine");
+        let options = MirrorOptions::new(&[]).unwrap().quarantine(&quarantine);
+
+        for content in [b"first", b"other"] {
+            fs::write(dir.path().join("old"), content).unwrap();
+
+            let found = extraneous(dir.path(), &[], &options);
+            remove_extraneous(dir.path(), &found, &options).unwrap();
+        }
+
+        assert!(!dir.path().join("old").exists());
+
+        let mut contents: Vec<Vec<u8>> = fs::read_dir(&quarantine).unwrap()
+            .map(|run| fs::read(run.unwrap().path().join("old")).unwrap())
+            .collect();
+        contents.sort();
+
+        assert_eq!(contents, [b"first".to_vec(), b"other".to_vec()]);
+    }
+
+    #[test]
+    fn remove_deletes_files_then_directories() {
+        let dir = TempDir::new();
+        write(dir.path(), "keep");
+        write(dir.path(), "gone/deeper/b");
+
+        let options = MirrorOptions::new(&[]).unwrap();
+        let found = extraneous(dir.path(), &manifest(&["keep"]), &options);
+        remove_extraneous(dir.path(), &found, &options).unwrap();
+
+        assert!(dir.path().join("keep").exists());
+        assert!(!dir.path().join("gone").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_are_reported_and_kept() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let dir = TempDir::new();
+        let name = OsStr::from_bytes(b"bad\xff");
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub").join(name), b"x").unwrap();
+
+        let reported = RefCell::new(Vec::new());
+        let found = find_extraneous(dir.path(), &[], &MirrorOptions::new(&[]).unwrap(), &|progress| {
+            if let Progress::SkippedLocal(path) = progress {
+                reported.borrow_mut().push(path.to_path_buf());
+            }
+        }).unwrap();
+
+        // The directory holding it isn't empty after all.
+        assert!(found.is_empty());
+        assert_eq!(reported.into_inner(), [dir.path().join("sub").join(name)]);
+    }
+}
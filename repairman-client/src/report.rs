@@ -0,0 +1,73 @@
+use std::{net::SocketAddr, path::Path};
+
+use repairman_common::{ErrorResponse, FileState, HashedFile, RequestVersion};
+
+/// Events reported while a `RepairSession` runs, the borrowed data is only valid for the callback.
+pub enum Progress<'a> {
+    Connected(SocketAddr),
+    TlsEstablished,
+    /// The server doesn't know the handshake, the session reconnects and uses protocol 0.1.
+    LegacyFallback,
+    Negotiated(RequestVersion),
+    Manifest(&'a [HashedFile]),
+    ManifestVerified,
+    /// Result of checking the local files, once per attempt.
+    Checked(&'a [(&'a HashedFile, FileState)]),
+    Resuming { path: &'a str, offset: u64 },
+    Patching(&'a str),
+    FileStarted(&'a str),
+    /// Bytes of the file received so far, as sent over the wire.
+    Received { path: &'a str, bytes: u64 },
+    FileDone(&'a str),
+    FileFailed(&'a str),
+    ServerError(&'a ErrorResponse),
+    /// A local path that isn't in the manifest, found in mirror mode.
+    Extraneous(&'a str),
+    /// A local path mirror mode leaves alone, its name isn't valid UTF-8 and can't be matched against the manifest.
+    SkippedLocal(&'a Path),
+}
+
+/// Outcome of a `RepairSession`, the states are from the last check against the manifest.
+pub struct RepairReport {
+    protocol: RequestVersion,
+    files: Vec<(HashedFile, FileState)>,
+    extraneous: Vec<String>,
+    attempts: u32,
+    bytes_received: u64,
+}
+
+impl RepairReport {
+    pub(crate) fn new(protocol: RequestVersion, files: Vec<(HashedFile, FileState)>, extraneous: Vec<String>, attempts: u32, bytes_received: u64) -> RepairReport {
+        RepairReport { protocol, files, extraneous, attempts, bytes_received }
+    }
+
+    pub fn get_protocol(&self) -> RequestVersion {
+        self.protocol
+    }
+
+    pub fn get_files(&self) -> &[(HashedFile, FileState)] {
+        &self.files
+    }
+
+    /// Local paths that weren't in the manifest, removed unless the session only verified.
+    pub fn get_extraneous(&self) -> &[String] {
+        &self.extraneous
+    }
+
+    pub fn get_attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn get_bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    pub fn count(&self, state: FileState) -> usize {
+        self.files.iter().filter(|f| f.1 == state).count()
+    }
+
+    /// Every file in the manifest is present with the right hash.
+    pub fn is_complete(&self) -> bool {
+        self.files.iter().all(|f| f.1 == FileState::Present)
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use blake2::Blake2s256;
+use digest::Digest;
+
+const CHECKPOINT_INTERVAL: u64 = 4 * 1024 * 1024;
+
+pub fn journal_path(path: &Path) -> PathBuf {
+    let mut os_path = path.to_path_buf().into_os_string();
+    os_path.push(".resume");
+    PathBuf::from(os_path)
+}
+
+/// Checks the resume journal of a partially downloaded file, returns the offset to continue from if the data on disk still matches it.
+pub fn resume_offset(path: &Path) -> Option<u64> {
+    let journal = fs::read_to_string(journal_path(path)).ok()?;
+    let mut parts = journal.split_whitespace();
+
+    let offset: u64 = parts.next()?.parse().ok()?;
+    let hash = parts.next()?;
+
+    if offset == 0 || fs::metadata(path).ok()?.len() < offset {
+        return None;
+    }
+
+    let mut file = File::open(path).ok()?.take(offset);
+    let mut hasher = Blake2s256::new();
+    let mut buffer = vec![0u8; 8192];
+
+    loop {
+        let n = file.read(&mut buffer).ok()?;
+        if n == 0 { break; }
+        hasher.update(&buffer[..n]);
+    }
+
+    if format!("{:x}", hasher.finalize()) == hash {
+        Some(offset)
+    } else {
+        None
+    }
+}
+
+/// Writes decompressed file data and records how far it got, so a dropped connection doesn't throw the progress away.
+pub struct JournaledWriter {
+    file: File,
+    path: PathBuf,
+    hasher: Blake2s256,
+    written: u64,
+    last_checkpoint: u64,
+}
+
+impl JournaledWriter {
+    /// Whatever is at `path` and isn't a regular file is removed first, writing through a symlink could land
+    /// outside of the target directory. A directory in the way is only removed if it's empty.
+    pub fn create(path: &Path) -> io::Result<JournaledWriter> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir(path)?,
+            Ok(metadata) if !metadata.is_file() => fs::remove_file(path)?,
+            _ => (),
+        }
+
+        let file = File::create(path)?;
+
+        Ok(JournaledWriter { file, path: path.to_path_buf(), hasher: Blake2s256::new(), written: 0, last_checkpoint: 0 })
+    }
+
+    pub fn resume(path: &Path, offset: u64) -> io::Result<JournaledWriter> {
+        if !fs::symlink_metadata(path)?.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Can't resume {path:?}, it isn't a regular file.")));
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        file.set_len(offset)?;
+
+        let mut hasher = Blake2s256::new();
+        let mut buffer = vec![0u8; 8192];
+
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 { break; }
+            hasher.update(&buffer[..n]);
+        }
+
+        Ok(JournaledWriter { file, path: path.to_path_buf(), hasher, written: offset, last_checkpoint: offset })
+    }
+
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+
+        let journal = format!("{} {:x}\n", self.written, self.hasher.clone().finalize());
+        fs::write(journal_path(&self.path), journal)?;
+
+        self.last_checkpoint = self.written;
+        Ok(())
+    }
+
+    pub fn complete(self) -> io::Result<()> {
+        let journal = journal_path(&self.path);
+
+        if journal.exists() {
+            fs::remove_file(journal)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for JournaledWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+
+        if self.written - self.last_checkpoint >= CHECKPOINT_INTERVAL {
+            self.checkpoint()?;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TempDir;
+
+    const MIB: usize = 1024 * 1024;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Writes `data` in 1 MiB pieces and leaves the file unfinished, like a dropped connection.
+    fn interrupted(path: &Path, data: &[u8]) {
+        let mut writer = JournaledWriter::create(path).unwrap();
+
+        for piece in data.chunks(MIB) {
+            writer.write_all(piece).unwrap();
+        }
+
+        writer.flush().unwrap();
+    }
+
+    #[test]
+    fn checkpoints_every_four_mib() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+
+        interrupted(&path, &content(3 * MIB));
+        assert!(!journal_path(&path).exists());
+        assert_eq!(resume_offset(&path), None);
+
+        interrupted(&path, &content(5 * MIB + 10));
+        assert_eq!(resume_offset(&path), Some(4 * MIB as u64));
+    }
+
+    #[test]
+    fn resumed_file_continues_from_the_offset() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        let data = content(6 * MIB);
+
+        interrupted(&path, &data[..5 * MIB]);
+        let offset = resume_offset(&path).unwrap();
+
+        let mut writer = JournaledWriter::resume(&path, offset).unwrap();
+        writer.write_all(&data[offset as usize..]).unwrap();
+        writer.complete().unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(!journal_path(&path).exists());
+    }
+
+    #[test]
+    fn complete_removes_the_journal() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+
+        let mut writer = JournaledWriter::create(&path).unwrap();
+        writer.write_all(&content(5 * MIB)).unwrap();
+        assert!(journal_path(&path).exists());
+
+        writer.complete().unwrap();
+        assert!(!journal_path(&path).exists());
+        assert_eq!(resume_offset(&path), None);
+    }
+
+    #[test]
+    fn changed_data_falls_back_to_the_start() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        interrupted(&path, &content(5 * MIB));
+
+        let mut data = fs::read(&path).unwrap();
+        data[100] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        assert_eq!(resume_offset(&path), None);
+    }
+
+    #[test]
+    fn truncated_file_falls_back_to_the_start() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        interrupted(&path, &content(5 * MIB));
+
+        File::options().write(true).open(&path).unwrap().set_len(4 * MIB as u64 - 1).unwrap();
+
+        assert_eq!(resume_offset(&path), None);
+    }
+
+    #[test]
+    fn damaged_journal_falls_back_to_the_start() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        interrupted(&path, &content(5 * MIB));
+
+        let journal = fs::read_to_string(journal_path(&path)).unwrap();
+        let (offset, hash) = journal.trim().split_once(' ').unwrap();
+
+        fs::write(journal_path(&path), format!("{offset} {}\n", "0".repeat(hash.len()))).unwrap();
+        assert_eq!(resume_offset(&path), None);
+
+        fs::write(journal_path(&path), format!("{offset}\n")).unwrap();
+        assert_eq!(resume_offset(&path), None);
+
+        fs::write(journal_path(&path), format!("{} {hash}\n", &offset[..offset.len() - 1])).unwrap();
+        assert_eq!(resume_offset(&path), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn create_replaces_a_symlink_instead_of_writing_through_it() {
+        let dir = TempDir::new();
+        let outside = TempDir::new();
+
+        let target = outside.path().join("target");
+        fs::write(&target, b"outside").unwrap();
+
+        let path = dir.path().join("file");
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+
+        let mut writer = JournaledWriter::create(&path).unwrap();
+        writer.write_all(b"repaired").unwrap();
+        writer.complete().unwrap();
+
+        assert!(fs::symlink_metadata(&path).unwrap().is_file());
+        assert_eq!(fs::read(&path).unwrap(), b"repaired");
+        assert_eq!(fs::read(&target).unwrap(), b"outside");
+
+        assert!(JournaledWriter::resume(&dir.path().join("missing"), 1).is_err());
+    }
+
+    #[test]
+    fn create_removes_an_empty_directory_in_the_way() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        fs::create_dir(&path).unwrap();
+
+        JournaledWriter::create(&path).unwrap().complete().unwrap();
+
+        assert!(path.is_file());
+    }
+}
@@ -0,0 +1,48 @@
+use std::{io, path::Path, sync::Arc};
+
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    TlsConnector,
+    client::TlsStream,
+    rustls::{
+        ClientConfig, RootCertStore,
+        pki_types::{CertificateDer, ServerName, pem::PemObject},
+    },
+};
+
+pub struct TlsOptions {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl TlsOptions {
+    /// Only certificates issued by the CAs in `ca_path` are trusted, the system roots aren't used.
+    pub fn load(ca_path: &Path, server_name: &str) -> io::Result<TlsOptions> {
+        let mut roots = RootCertStore::empty();
+
+        let certs = CertificateDer::pem_file_iter(ca_path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to read CA certificates from {:?}: {}", ca_path, e)))?;
+
+        for cert in certs {
+            let cert = cert.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid CA certificate in {:?}: {}", ca_path, e)))?;
+            roots.add(cert).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        }
+
+        if roots.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No CA certificates found in {:?}", ca_path)));
+        }
+
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+        Ok(TlsOptions { connector: TlsConnector::from(Arc::new(config)), server_name })
+    }
+
+    pub async fn connect(&self, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+        self.connector.connect(self.server_name.clone(), stream).await
+    }
+}
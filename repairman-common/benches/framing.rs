@@ -0,0 +1,74 @@
+use std::hint::black_box;
+
+use bytes::BytesMut;
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use tokio_util::codec::{Decoder, Encoder};
+
+use repairman_common::*;
+
+const CHUNK_SIZE: usize = 32768;
+const MESSAGES: usize = 64;
+
+const CODECS: [(&str, RequestVersion, bool); 3] = [
+    ("header 0.1", RequestVersion::ZEROpOne, false),
+    ("frame 0.3", RequestVersion::ZEROpThree, false),
+    ("frame 0.3 checksum", RequestVersion::ZEROpThree, true),
+];
+
+fn encoded(codec: &mut MessageCodec, request_type: RequestType, body: &[u8]) -> BytesMut {
+    let mut buffer = BytesMut::new();
+
+    for _ in 0..MESSAGES {
+        codec.encode(Frame::new(request_type, b"", body), &mut buffer).unwrap();
+    }
+
+    buffer
+}
+
+fn bench_messages(c: &mut Criterion, name: &str, request_type: RequestType, body: &[u8]) {
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+
+    for (codec_name, version, checksum) in CODECS {
+        let mut codec = MessageCodec::new(version, checksum);
+        println!("{name}, {codec_name}: {} bytes per message on the wire", encoded(&mut codec, request_type, body).len() / MESSAGES);
+
+        group.bench_function(format!("encode {codec_name}"), |b| {
+            let mut buffer = BytesMut::with_capacity(MESSAGES * (body.len() + 64));
+            b.iter(|| {
+                buffer.clear();
+                for _ in 0..MESSAGES {
+                    codec.encode(Frame::new(request_type, b"", black_box(body)), &mut buffer).unwrap();
+                }
+            });
+        });
+
+        let wire = encoded(&mut codec, request_type, body);
+
+        group.bench_function(format!("decode {codec_name}"), |b| {
+            b.iter(|| {
+                let mut buffer = wire.clone();
+                while let Some(message) = codec.decode(&mut buffer).unwrap() {
+                    black_box(message);
+                }
+            });
+        });
+    }
+
+    group.finish();
+}
+
+fn chunks(c: &mut Criterion) {
+    bench_messages(c, "32 KiB chunks", RequestType::Chunk, &vec![0xa5u8; CHUNK_SIZE]);
+}
+
+fn empty_messages(c: &mut Criterion) {
+    bench_messages(c, "empty end-file", RequestType::EndFile, b"");
+}
+
+fn delta_copies(c: &mut Criterion) {
+    bench_messages(c, "delta copies", RequestType::DeltaCopy, &42u64.to_be_bytes());
+}
+
+criterion_group!(benches, chunks, empty_messages, delta_copies);
+criterion_main!(benches);
@@ -0,0 +1,383 @@
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{Message, Request, RequestType, RequestVersion, create_header, parse_header};
+
+pub const FRAME_MAGIC: [u8; 2] = *b"RM";
+
+const FLAG_CHECKSUM: u8 = 0x01;
+const HEADER_SIZE: usize = 64;
+const MAX_VARINT_SIZE: usize = 10;
+
+/// An outgoing message, borrows the file name and body so chunks don't get copied twice.
+pub struct Frame<'a> {
+    request_type: RequestType,
+    file_name: &'a [u8],
+    body: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(request_type: RequestType, file_name: &'a [u8], body: &'a [u8]) -> Frame<'a> {
+        Frame { request_type, file_name, body }
+    }
+}
+
+fn part_size(part: &[u8], what: &str) -> io::Result<u32> {
+    u32::try_from(part.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{what} is too large for a message.")))
+}
+
+/// The 64-byte text header used up to protocol 0.2.
+pub struct HeaderCodec {
+    version: RequestVersion,
+    pending: Option<Request>,
+}
+
+impl HeaderCodec {
+    pub fn new(version: RequestVersion) -> HeaderCodec {
+        HeaderCodec { version, pending: None }
+    }
+}
+
+impl Encoder<Frame<'_>> for HeaderCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame<'_>, dst: &mut BytesMut) -> io::Result<()> {
+        let file_name_size = part_size(frame.file_name, "File name")?;
+        let body_size = part_size(frame.body, "Body")?;
+
+        dst.reserve(HEADER_SIZE + frame.file_name.len() + frame.body.len());
+        dst.put_slice(&create_header(self.version, frame.request_type, file_name_size, body_size));
+        dst.put_slice(frame.file_name);
+        dst.put_slice(frame.body);
+
+        Ok(())
+    }
+}
+
+impl Decoder for HeaderCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        // The header is parsed once, not again for every read while the body is still arriving.
+        let request = match self.pending.take() {
+            Some(r) => r,
+            None => {
+                if src.len() < HEADER_SIZE {
+                    return Ok(None);
+                }
+
+                let header: [u8; HEADER_SIZE] = src[..HEADER_SIZE].try_into()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Couldn't read out the header."))?;
+                let request = parse_header(&header)?;
+                src.advance(HEADER_SIZE);
+                request
+            },
+        };
+
+        let file_name_size = *request.get_file_name_size();
+        let body_size = *request.get_body_size();
+
+        if src.len() < file_name_size + body_size {
+            self.pending = Some(request);
+            return Ok(None);
+        }
+
+        let file_name = src.split_to(file_name_size).to_vec();
+        let body = src.split_to(body_size).to_vec();
+
+        Ok(Some(Message::new(request, file_name, body)))
+    }
+}
+
+/// Binary framing of protocol 0.3:
+/// magic "RM", a flags byte, varint type, varint file name size, varint body size, file name, body,
+/// and a big endian CRC32 over everything after the magic if the checksum flag is set.
+pub struct FrameCodec {
+    checksum: bool,
+}
+
+impl FrameCodec {
+    pub fn new(checksum: bool) -> FrameCodec {
+        FrameCodec { checksum }
+    }
+}
+
+fn put_varint(dst: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        dst.put_u8((value as u8) | 0x80);
+        value >>= 7;
+    }
+    dst.put_u8(value as u8);
+}
+
+/// Reads a LEB128 varint at `pos`, `None` if it isn't complete yet.
+fn get_varint(src: &[u8], pos: &mut usize) -> io::Result<Option<u64>> {
+    let mut value = 0u64;
+
+    for i in 0..MAX_VARINT_SIZE {
+        let byte = match src.get(*pos + i) {
+            Some(b) => *b,
+            None => return Ok(None),
+        };
+
+        value |= u64::from(byte & 0x7f) << (7 * i);
+
+        if byte & 0x80 == 0 {
+            *pos += i + 1;
+            return Ok(Some(value));
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::InvalidData, "Varint in frame is too long."))
+}
+
+fn get_size(src: &[u8], pos: &mut usize) -> io::Result<Option<usize>> {
+    match get_varint(src, pos)? {
+        Some(size) if size > u64::from(u32::MAX) => Err(io::Error::new(io::ErrorKind::InvalidData, "Frame part is too large.")),
+        Some(size) => Ok(Some(size as usize)),
+        None => Ok(None),
+    }
+}
+
+impl Encoder<Frame<'_>> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame<'_>, dst: &mut BytesMut) -> io::Result<()> {
+        part_size(frame.file_name, "File name")?;
+        part_size(frame.body, "Body")?;
+
+        dst.reserve(3 + 3 * MAX_VARINT_SIZE + frame.file_name.len() + frame.body.len() + 4);
+
+        dst.put_slice(&FRAME_MAGIC);
+        let start = dst.len();
+
+        dst.put_u8(if self.checksum { FLAG_CHECKSUM } else { 0 });
+        put_varint(dst, u64::from(frame.request_type.to_u8()));
+        put_varint(dst, frame.file_name.len() as u64);
+        put_varint(dst, frame.body.len() as u64);
+        dst.put_slice(frame.file_name);
+        dst.put_slice(frame.body);
+
+        if self.checksum {
+            let checksum = crc32fast::hash(&dst[start..]);
+            dst.put_u32(checksum);
+        }
+
+        Ok(())
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        if src.len() < 3 {
+            return Ok(None);
+        }
+
+        if src[..2] != FRAME_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame doesn't start with the magic number."));
+        }
+
+        let flags = src[2];
+        if flags & !FLAG_CHECKSUM != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame has unknown flags set."));
+        }
+
+        let mut pos = 3;
+
+        let Some(code) = get_varint(src, &mut pos)? else { return Ok(None) };
+        let Some(file_name_size) = get_size(src, &mut pos)? else { return Ok(None) };
+        let Some(body_size) = get_size(src, &mut pos)? else { return Ok(None) };
+
+        let request_type = u8::try_from(code).ok().and_then(RequestType::from_u8)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid request type was recieved."))?;
+
+        let checksum_size = if flags & FLAG_CHECKSUM != 0 { 4 } else { 0 };
+        let total = pos + file_name_size + body_size + checksum_size;
+
+        if src.len() < total {
+            return Ok(None);
+        }
+
+        let mut frame = src.split_to(total);
+
+        if checksum_size > 0 {
+            let expected = u32::from_be_bytes(frame[total - 4..].try_into()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Couldn't read out the frame checksum."))?);
+
+            if crc32fast::hash(&frame[2..total - 4]) != expected {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame checksum doesn't match."));
+            }
+        }
+
+        frame.advance(pos);
+        let file_name = frame.split_to(file_name_size).to_vec();
+        let body = frame.split_to(body_size).to_vec();
+
+        let request = Request::new(RequestVersion::ZEROpThree, request_type, file_name_size, body_size);
+
+        Ok(Some(Message::new(request, file_name, body)))
+    }
+}
+
+/// Picks the wire format belonging to a protocol version.
+pub enum MessageCodec {
+    Header(HeaderCodec),
+    Frame(FrameCodec),
+}
+
+impl MessageCodec {
+    pub fn new(version: RequestVersion, checksum: bool) -> MessageCodec {
+        match version {
+            RequestVersion::ZEROpOne | RequestVersion::ZEROpTwo => MessageCodec::Header(HeaderCodec::new(version)),
+            RequestVersion::ZEROpThree => MessageCodec::Frame(FrameCodec::new(checksum)),
+        }
+    }
+}
+
+impl Encoder<Frame<'_>> for MessageCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame<'_>, dst: &mut BytesMut) -> io::Result<()> {
+        match self {
+            MessageCodec::Header(codec) => codec.encode(frame, dst),
+            MessageCodec::Frame(codec) => codec.encode(frame, dst),
+        }
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        match self {
+            MessageCodec::Header(codec) => codec.decode(src),
+            MessageCodec::Frame(codec) => codec.decode(src),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode<C: for<'a> Encoder<Frame<'a>, Error = io::Error>>(codec: &mut C, request_type: RequestType, file_name: &[u8], body: &[u8]) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        codec.encode(Frame::new(request_type, file_name, body), &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut codec = HeaderCodec::new(RequestVersion::ZEROpTwo);
+        let mut buffer = encode(&mut codec, RequestType::GiveFiles, b"a/b.txt", b"content");
+
+        let message = codec.decode(&mut buffer).unwrap().unwrap();
+
+        assert_eq!(message.get_type(), &RequestType::GiveFiles);
+        assert_eq!(message.get_version(), &RequestVersion::ZEROpTwo);
+        assert_eq!(message.get_file_name(), b"a/b.txt");
+        assert_eq!(message.get_body(), b"content");
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn header_get_hashes_keeps_name_and_tag() {
+        let mut codec = HeaderCodec::new(RequestVersion::ZEROpTwo);
+        let mut buffer = encode(&mut codec, RequestType::GetHashes, b"repo", b"v1");
+        buffer.extend_from_slice(&encode(&mut codec, RequestType::Disconnect, b"", b""));
+
+        let message = codec.decode(&mut buffer).unwrap().unwrap();
+
+        assert_eq!(message.get_file_name(), b"repo");
+        assert_eq!(message.get_body(), b"v1");
+        assert_eq!(codec.decode(&mut buffer).unwrap().unwrap().get_type(), &RequestType::Disconnect);
+    }
+
+    #[test]
+    fn header_waits_for_the_whole_message() {
+        let mut codec = HeaderCodec::new(RequestVersion::ZEROpTwo);
+        let encoded = encode(&mut codec, RequestType::Chunk, b"", &[7u8; 100]);
+
+        let mut buffer = BytesMut::new();
+        for byte in &encoded[..encoded.len() - 1] {
+            buffer.put_u8(*byte);
+            assert!(codec.decode(&mut buffer).unwrap().is_none());
+        }
+
+        buffer.put_u8(encoded[encoded.len() - 1]);
+        assert_eq!(codec.decode(&mut buffer).unwrap().unwrap().get_body(), &[7u8; 100]);
+    }
+
+    #[test]
+    fn header_with_wrong_protocol_name_is_refused() {
+        let mut codec = HeaderCodec::new(RequestVersion::ZEROpTwo);
+        let mut buffer = encode(&mut codec, RequestType::Chunk, b"", b"");
+        buffer[0] = b'X';
+
+        assert_eq!(codec.decode(&mut buffer).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        for checksum in [false, true] {
+            let mut codec = FrameCodec::new(checksum);
+            let body = vec![3u8; 70_000];
+            let mut buffer = encode(&mut codec, RequestType::GetFiles, b"name", &body);
+            buffer.extend_from_slice(&encode(&mut codec, RequestType::Disconnect, b"", b""));
+
+            let first = codec.decode(&mut buffer).unwrap().unwrap();
+            assert_eq!(first.get_type(), &RequestType::GetFiles);
+            assert_eq!(first.get_file_name(), b"name");
+            assert_eq!(first.get_body(), body.as_slice());
+
+            let second = codec.decode(&mut buffer).unwrap().unwrap();
+            assert_eq!(second.get_type(), &RequestType::Disconnect);
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn frame_waits_for_the_whole_frame() {
+        let mut codec = FrameCodec::new(true);
+        let encoded = encode(&mut codec, RequestType::Chunk, b"f", &[1u8; 300]);
+
+        let mut buffer = BytesMut::from(&encoded[..encoded.len() - 1]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+
+        buffer.put_u8(encoded[encoded.len() - 1]);
+        assert!(codec.decode(&mut buffer).unwrap().is_some());
+    }
+
+    #[test]
+    fn frame_with_flipped_byte_fails_the_checksum() {
+        let mut codec = FrameCodec::new(true);
+        let mut buffer = encode(&mut codec, RequestType::Chunk, b"", b"some body");
+        let last_body_byte = buffer.len() - 5;
+        buffer[last_body_byte] ^= 0x01;
+
+        assert_eq!(codec.decode(&mut buffer).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn malformed_frames_are_refused() {
+        let mut codec = FrameCodec::new(false);
+
+        // Wrong magic.
+        assert!(codec.decode(&mut BytesMut::from(&b"XX\0\x01\0\0"[..])).is_err());
+        // Unknown flag.
+        assert!(codec.decode(&mut BytesMut::from(&b"RM\x80\x01\0\0"[..])).is_err());
+        // Unknown request type.
+        assert!(codec.decode(&mut BytesMut::from(&b"RM\0\x7f\0\0"[..])).is_err());
+        // Varint longer than a u64.
+        assert!(codec.decode(&mut BytesMut::from(&b"RM\0\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"[..])).is_err());
+        // Body size above u32::MAX.
+        assert!(codec.decode(&mut BytesMut::from(&b"RM\0\x01\0\x80\x80\x80\x80\x10"[..])).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use std::io;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{ErrorResponse, Frame, MessageCodec, Request, RequestType, RequestVersion};
+
+const READ_SIZE: usize = 64 * 1024;
+
+/// A complete message: header, file name and body.
+pub struct Message {
+    request: Request,
+    file_name: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(request: Request, file_name: Vec<u8>, body: Vec<u8>) -> Message {
+        Message { request, file_name, body }
+    }
+
+    pub fn get_type(&self) -> &RequestType {
+        self.request.get_type()
+    }
+
+    pub fn get_version(&self) -> &RequestVersion {
+        self.request.get_version()
+    }
+
+    pub fn get_file_name(&self) -> &[u8] {
+        &self.file_name
+    }
+
+    pub fn get_file_name_str(&self) -> io::Result<&str> {
+        str::from_utf8(&self.file_name).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Couldn't convert file name to string."))
+    }
+
+    pub fn get_body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+
+    /// Turns an ERROR message into an `io::Error`, any other message is returned as is.
+    pub fn into_result(self) -> io::Result<Message> {
+        if self.get_type() == &RequestType::Error {
+            return Err(io::Error::other(ErrorResponse::from_message(&self)?));
+        }
+
+        Ok(self)
+    }
+}
+
+/// Wraps a stream and frames every message in the format of the version negotiated for it.
+pub struct Connection<S> {
+    stream: S,
+    version: RequestVersion,
+    checksum: bool,
+    codec: MessageCodec,
+    read_buffer: BytesMut,
+    write_buffer: BytesMut,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S) -> Connection<S> {
+        Connection {
+            stream,
+            version: RequestVersion::ZEROpOne,
+            checksum: false,
+            codec: MessageCodec::new(RequestVersion::ZEROpOne, false),
+            read_buffer: BytesMut::with_capacity(READ_SIZE),
+            write_buffer: BytesMut::new(),
+        }
+    }
+
+    pub fn get_version(&self) -> RequestVersion {
+        self.version
+    }
+
+    /// Switches the wire format, anything already read stays buffered for the new one.
+    pub fn set_version(&mut self, version: RequestVersion) {
+        self.version = version;
+        self.codec = MessageCodec::new(version, self.checksum);
+    }
+
+    /// Adds a checksum to every sent frame, only the binary framing of 0.3 carries one.
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
+        self.codec = MessageCodec::new(self.version, checksum);
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub async fn send(&mut self, request_type: RequestType, file_name: &[u8], body: &[u8]) -> io::Result<()> {
+        self.write_buffer.clear();
+        self.codec.encode(Frame::new(request_type, file_name, body), &mut self.write_buffer)?;
+
+        self.stream.write_all(&self.write_buffer).await
+    }
+
+    pub async fn send_error(&mut self, error: &ErrorResponse) -> io::Result<()> {
+        self.send(RequestType::Error, error.get_file_name().as_bytes(), &error.encode_body()).await
+    }
+
+    pub async fn receive(&mut self) -> io::Result<Message> {
+        loop {
+            if let Some(message) = self.codec.decode(&mut self.read_buffer)? {
+                return Ok(message);
+            }
+
+            self.read_buffer.reserve(READ_SIZE);
+
+            if self.stream.read_buf(&mut self.read_buffer).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before a complete message was recieved."));
+            }
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use blake2::Blake2s256;
+use digest::Digest;
+
+pub const DELTA_BLOCK_SIZE: u32 = 32768;
+/// The sender of a delta holds a block and a literal in memory, the peer picks the block size.
+pub const MAX_DELTA_BLOCK_SIZE: u32 = 1024 * 1024;
+const MAX_LITERAL_SIZE: usize = 32768;
+const SIGNATURE_SIZE: usize = 36;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct BlockSignature {
+    weak: u32,
+    strong: [u8; 32],
+}
+
+impl BlockSignature {
+    pub fn new(block: &[u8]) -> BlockSignature {
+        BlockSignature {
+            weak: RollingChecksum::new(block).digest(),
+            strong: strong_hash(block),
+        }
+    }
+
+    pub fn get_weak(&self) -> u32 {
+        self.weak
+    }
+
+    pub fn get_strong(&self) -> &[u8; 32] {
+        &self.strong
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum DeltaOp {
+    Copy(u64),
+    Literal(Vec<u8>),
+}
+
+/// Adler-32 style checksum as used by rsync, can be moved one byte forward in constant time.
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(block: &[u8]) -> RollingChecksum {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        let len = block.len() as u32;
+
+        for (i, byte) in block.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+
+        RollingChecksum { a: a & 0xffff, b: b & 0xffff, len }
+    }
+
+    pub fn roll(&mut self, out_byte: u8, in_byte: u8) {
+        self.a = self.a.wrapping_sub(out_byte as u32).wrapping_add(in_byte as u32) & 0xffff;
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out_byte as u32)).wrapping_add(self.a) & 0xffff;
+    }
+
+    pub fn digest(&self) -> u32 {
+        self.a | (self.b << 16)
+    }
+}
+
+fn strong_hash(block: &[u8]) -> [u8; 32] {
+    Blake2s256::digest(block).into()
+}
+
+pub fn compute_signatures<R: Read>(reader: &mut R, block_size: u32) -> io::Result<Vec<BlockSignature>> {
+    let mut signatures = Vec::new();
+    let mut buffer = vec![0u8; block_size as usize];
+
+    loop {
+        let n = read_full(reader, &mut buffer)?;
+        if n == 0 { break; }
+
+        signatures.push(BlockSignature::new(&buffer[..n]));
+
+        if n < buffer.len() { break; }
+    }
+
+    Ok(signatures)
+}
+
+/// Body of a GET-DELTA request: block size followed by weak and strong checksum of every block.
+pub fn encode_signatures(block_size: u32, signatures: &[BlockSignature]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + signatures.len() * SIGNATURE_SIZE);
+    body.extend_from_slice(&block_size.to_be_bytes());
+
+    for signature in signatures {
+        body.extend_from_slice(&signature.weak.to_be_bytes());
+        body.extend_from_slice(&signature.strong);
+    }
+
+    body
+}
+
+pub fn decode_signatures(body: &[u8]) -> io::Result<(u32, Vec<BlockSignature>)> {
+    if body.len() < 4 || !(body.len() - 4).is_multiple_of(SIGNATURE_SIZE) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Delta signature body has an invalid size."));
+    }
+
+    let block_size = u32::from_be_bytes(body[..4].try_into().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Couldn't read out block size."))?);
+
+    if block_size == 0 || block_size > MAX_DELTA_BLOCK_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Delta block size {block_size} is out of range.")));
+    }
+
+    let signatures = body[4..].chunks_exact(SIGNATURE_SIZE).map(|chunk| {
+        let mut strong = [0u8; 32];
+        strong.copy_from_slice(&chunk[4..]);
+
+        BlockSignature {
+            weak: u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
+            strong,
+        }
+    }).collect();
+
+    Ok((block_size, signatures))
+}
+
+/// Walks through `reader` and calls `emit` with the operations needed to rebuild it out of the blocks described by `signatures`.
+pub fn compute_delta<R, F>(reader: &mut R, block_size: u32, signatures: &[BlockSignature], mut emit: F) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(DeltaOp) -> io::Result<()>,
+{
+    let block_size = block_size as usize;
+    let mut lookup: HashMap<u32, Vec<usize>> = HashMap::with_capacity(signatures.len());
+
+    for (index, signature) in signatures.iter().enumerate() {
+        lookup.entry(signature.weak).or_default().push(index);
+    }
+
+    let mut data: Vec<u8> = Vec::with_capacity(block_size + MAX_LITERAL_SIZE);
+    let mut read_buffer = vec![0u8; block_size.max(8192)];
+    let mut pos = 0;
+    let mut eof = false;
+    let mut checksum: Option<RollingChecksum> = None;
+
+    loop {
+        while !eof && data.len() < pos + block_size + 1 {
+            let n = reader.read(&mut read_buffer)?;
+            if n == 0 {
+                eof = true;
+            } else {
+                data.extend_from_slice(&read_buffer[..n]);
+            }
+        }
+
+        let window_end = (pos + block_size).min(data.len());
+
+        if pos >= window_end {
+            break;
+        }
+
+        let window = &data[pos..window_end];
+
+        let weak = match checksum {
+            Some(ref c) => c.digest(),
+            None => {
+                let c = RollingChecksum::new(window);
+                let digest = c.digest();
+                checksum = Some(c);
+                digest
+            },
+        };
+
+        let matched = lookup.get(&weak).and_then(|indices| {
+            let strong = strong_hash(window);
+            indices.iter().find(|i| signatures[**i].strong == strong).copied()
+        });
+
+        if let Some(index) = matched {
+            if pos > 0 {
+                emit(DeltaOp::Literal(data[..pos].to_vec()))?;
+            }
+            emit(DeltaOp::Copy(index as u64))?;
+
+            data.drain(..window_end);
+            pos = 0;
+            checksum = None;
+            continue;
+        }
+
+        if window_end < pos + block_size {
+            // Short tail without a match, everything left is literal data.
+            emit(DeltaOp::Literal(data.split_off(0)))?;
+            break;
+        }
+
+        if let Some(ref mut c) = checksum {
+            match data.get(window_end) {
+                Some(in_byte) => c.roll(data[pos], *in_byte),
+                None => checksum = None,
+            }
+        }
+
+        pos += 1;
+
+        if pos >= MAX_LITERAL_SIZE {
+            emit(DeltaOp::Literal(data[..pos].to_vec()))?;
+            data.drain(..pos);
+            pos = 0;
+        }
+    }
+
+    Ok(())
+}
+
+/// Rebuilds a file out of the blocks of an old copy and the operations produced by `compute_delta`.
+pub struct DeltaPatcher<R: Read + Seek, W: Write> {
+    old: R,
+    new: W,
+    block_size: u64,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read + Seek, W: Write> DeltaPatcher<R, W> {
+    pub fn new(old: R, new: W, block_size: u32) -> DeltaPatcher<R, W> {
+        DeltaPatcher { old, new, block_size: block_size as u64, buffer: vec![0u8; block_size as usize] }
+    }
+
+    pub fn apply(&mut self, op: DeltaOp) -> io::Result<()> {
+        match op {
+            DeltaOp::Copy(index) => {
+                let offset = index.checked_mul(self.block_size)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Delta block index is out of range."))?;
+
+                self.old.seek(SeekFrom::Start(offset))?;
+                let n = read_full(&mut self.old, &mut self.buffer)?;
+
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Delta references a block past the end of the old file."));
+                }
+
+                self.new.write_all(&self.buffer[..n])
+            },
+            DeltaOp::Literal(data) => self.new.write_all(&data),
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.new.flush()?;
+        Ok(self.new)
+    }
+}
+
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const BLOCK: u32 = 64;
+
+    fn pseudo_random(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    fn delta(old: &[u8], new: &[u8]) -> Vec<DeltaOp> {
+        let signatures = compute_signatures(&mut Cursor::new(old), BLOCK).unwrap();
+        let (block_size, signatures) = decode_signatures(&encode_signatures(BLOCK, &signatures)).unwrap();
+
+        let mut ops = Vec::new();
+        compute_delta(&mut Cursor::new(new), block_size, &signatures, |op| { ops.push(op); Ok(()) }).unwrap();
+        ops
+    }
+
+    fn patch(old: &[u8], ops: Vec<DeltaOp>) -> io::Result<Vec<u8>> {
+        let mut patcher = DeltaPatcher::new(Cursor::new(old), Vec::new(), BLOCK);
+        for op in ops {
+            patcher.apply(op)?;
+        }
+        patcher.finish()
+    }
+
+    #[test]
+    fn rolling_checksum_matches_a_fresh_one() {
+        let data = pseudo_random(200, 1);
+        let mut rolling = RollingChecksum::new(&data[..BLOCK as usize]);
+
+        for start in 1..(data.len() - BLOCK as usize) {
+            rolling.roll(data[start - 1], data[start - 1 + BLOCK as usize]);
+            assert_eq!(rolling.digest(), RollingChecksum::new(&data[start..start + BLOCK as usize]).digest());
+        }
+    }
+
+    #[test]
+    fn patch_rebuilds_the_new_file() {
+        let old = pseudo_random(10_000, 2);
+
+        let mut new = old.clone();
+        new.splice(1000..1000, pseudo_random(37, 3));
+        new.drain(5000..5100);
+        new.extend_from_slice(&pseudo_random(50, 4));
+
+        let ops = delta(&old, &new);
+
+        assert!(ops.iter().any(|op| matches!(op, DeltaOp::Copy(_))));
+        assert_eq!(patch(&old, ops).unwrap(), new);
+    }
+
+    #[test]
+    fn unchanged_file_is_only_copies() {
+        let old = pseudo_random(BLOCK as usize * 10, 5);
+        let ops = delta(&old, &old);
+
+        assert!(ops.iter().all(|op| matches!(op, DeltaOp::Copy(_))));
+        assert_eq!(patch(&old, ops).unwrap(), old);
+    }
+
+    #[test]
+    fn empty_old_file_is_all_literals() {
+        let new = pseudo_random(100_000, 6);
+        let ops = delta(&[], &new);
+
+        assert!(ops.iter().all(|op| matches!(op, DeltaOp::Literal(data) if data.len() <= MAX_LITERAL_SIZE)));
+        assert_eq!(patch(&[], ops).unwrap(), new);
+    }
+
+    #[test]
+    fn copy_past_the_old_file_is_refused() {
+        let old = pseudo_random(100, 7);
+
+        assert!(patch(&old, vec![DeltaOp::Copy(5)]).is_err());
+        assert!(patch(&old, vec![DeltaOp::Copy(u64::MAX)]).is_err());
+    }
+
+    #[test]
+    fn malformed_signatures_are_refused() {
+        assert!(decode_signatures(b"").is_err());
+        assert!(decode_signatures(&[0, 0, 0, 0]).is_err());
+        assert!(decode_signatures(&[0, 0, 0x80, 0, 1]).is_err());
+        assert!(decode_signatures(&(MAX_DELTA_BLOCK_SIZE + 1).to_be_bytes()).is_err());
+        assert!(decode_signatures(&u32::MAX.to_be_bytes()).is_err());
+        assert!(decode_signatures(&MAX_DELTA_BLOCK_SIZE.to_be_bytes()).is_ok());
+    }
+}
@@ -0,0 +1,141 @@
+use std::io;
+
+use crate::Message;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ErrorCode {
+    UnknownFile,
+    Io,
+    VersionMismatch,
+    BadRequest,
+    UnknownRepository,
+    UnknownRelease,
+    Other(u16),
+}
+
+impl ErrorCode {
+    pub fn to_u16(self) -> u16 {
+        match self {
+            ErrorCode::UnknownFile => 1,
+            ErrorCode::Io => 2,
+            ErrorCode::VersionMismatch => 3,
+            ErrorCode::BadRequest => 4,
+            ErrorCode::UnknownRepository => 5,
+            ErrorCode::UnknownRelease => 6,
+            ErrorCode::Other(code) => code,
+        }
+    }
+
+    pub fn from_u16(code: u16) -> ErrorCode {
+        match code {
+            1 => ErrorCode::UnknownFile,
+            2 => ErrorCode::Io,
+            3 => ErrorCode::VersionMismatch,
+            4 => ErrorCode::BadRequest,
+            5 => ErrorCode::UnknownRepository,
+            6 => ErrorCode::UnknownRelease,
+            code => ErrorCode::Other(code),
+        }
+    }
+
+    /// Code to answer a failed request with, `None` if the peer is gone anyway.
+    pub fn from_io_error(err: &io::Error) -> Option<ErrorCode> {
+        if let Some(ProtocolError::UnsupportedVersion(_)) = err.get_ref().and_then(|e| e.downcast_ref::<ProtocolError>()) {
+            return Some(ErrorCode::VersionMismatch);
+        }
+
+        match err.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => Some(ErrorCode::BadRequest),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorCode::UnknownFile => write!(f, "Unknown File"),
+            ErrorCode::Io => write!(f, "I/O Error"),
+            ErrorCode::VersionMismatch => write!(f, "Version Mismatch"),
+            ErrorCode::BadRequest => write!(f, "Bad Request"),
+            ErrorCode::UnknownRepository => write!(f, "Unknown Repository"),
+            ErrorCode::UnknownRelease => write!(f, "Unknown Release"),
+            ErrorCode::Other(code) => write!(f, "Error {code}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    UnsupportedVersion(String),
+}
+
+impl core::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::UnsupportedVersion(v) => write!(f, "Unsupported protocol version: {v:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// ERROR message, the file name field names the file it is about (may be empty), the body is a u16 code followed by the message.
+#[derive(Debug)]
+pub struct ErrorResponse {
+    code: ErrorCode,
+    file_name: String,
+    message: String,
+}
+
+impl ErrorResponse {
+    pub fn new(code: ErrorCode, file_name: &str, message: &str) -> ErrorResponse {
+        ErrorResponse { code, file_name: file_name.to_string(), message: message.to_string() }
+    }
+
+    pub fn get_code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub fn get_file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn encode_body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(self.message.len() + 2);
+        body.extend_from_slice(&self.code.to_u16().to_be_bytes());
+        body.extend_from_slice(self.message.as_bytes());
+
+        body
+    }
+
+    pub fn from_message(message: &Message) -> io::Result<ErrorResponse> {
+        let body = message.get_body();
+
+        if body.len() < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Error response is missing its code."));
+        }
+
+        Ok(ErrorResponse {
+            code: ErrorCode::from_u16(u16::from_be_bytes([body[0], body[1]])),
+            file_name: String::from_utf8_lossy(message.get_file_name()).into_owned(),
+            message: String::from_utf8_lossy(&body[2..]).into_owned(),
+        })
+    }
+}
+
+impl core::fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.file_name.is_empty() {
+            write!(f, "{}: {}", self.code, self.message)
+        } else {
+            write!(f, "{} ({}): {}", self.code, self.file_name, self.message)
+        }
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
@@ -0,0 +1,190 @@
+use std::io;
+
+use crate::{ProtocolError, RequestVersion};
+
+pub const SUPPORTED_VERSIONS: [RequestVersion; 3] = [RequestVersion::ZEROpOne, RequestVersion::ZEROpTwo, RequestVersion::ZEROpThree];
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Capability {
+    Deflate,
+    Delta,
+    Resume,
+    Signature,
+    Checksum,
+    Repositories,
+    Releases,
+    /// GIVE-HASHES only carries the entry count, the entries follow in CHUNK messages up to an END-FILE.
+    ManifestStream,
+    /// Manifest entries and GET-FILES names are length-prefixed instead of split on spaces and newlines.
+    LengthPrefixed,
+    /// Length-prefixed manifest entries also carry kind, size, mode and modification time.
+    Metadata,
+    Other(String),
+}
+
+impl Capability {
+    pub fn parse(name: &str) -> Capability {
+        match name {
+            "deflate" => Capability::Deflate,
+            "delta" => Capability::Delta,
+            "resume" => Capability::Resume,
+            "signature" => Capability::Signature,
+            "checksum" => Capability::Checksum,
+            "repositories" => Capability::Repositories,
+            "releases" => Capability::Releases,
+            "manifest-stream" => Capability::ManifestStream,
+            "length-prefixed" => Capability::LengthPrefixed,
+            "metadata" => Capability::Metadata,
+            other => Capability::Other(other.to_string()),
+        }
+    }
+}
+
+impl core::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Capability::Deflate => write!(f, "deflate"),
+            Capability::Delta => write!(f, "delta"),
+            Capability::Resume => write!(f, "resume"),
+            Capability::Signature => write!(f, "signature"),
+            Capability::Checksum => write!(f, "checksum"),
+            Capability::Repositories => write!(f, "repositories"),
+            Capability::Releases => write!(f, "releases"),
+            Capability::ManifestStream => write!(f, "manifest-stream"),
+            Capability::LengthPrefixed => write!(f, "length-prefixed"),
+            Capability::Metadata => write!(f, "metadata"),
+            Capability::Other(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Body of a HELLO message, the client lists everything it supports and the server answers with the single version it picked.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Hello {
+    versions: Vec<RequestVersion>,
+    capabilities: Vec<Capability>,
+}
+
+impl Hello {
+    pub fn new(versions: &[RequestVersion], capabilities: &[Capability]) -> Hello {
+        Hello { versions: versions.to_vec(), capabilities: capabilities.to_vec() }
+    }
+
+    pub fn get_versions(&self) -> &[RequestVersion] {
+        &self.versions
+    }
+
+    pub fn get_capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    pub fn has_capability(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    pub fn highest_version(&self) -> Option<RequestVersion> {
+        self.versions.iter().max().copied()
+    }
+
+    /// Highest version and the capabilities both sides know, fails with `ProtocolError::UnsupportedVersion` if there's no common version.
+    pub fn negotiate(&self, other: &Hello) -> io::Result<Hello> {
+        let version = self.versions.iter()
+            .filter(|v| other.versions.contains(v))
+            .max()
+            .copied()
+            .ok_or_else(|| {
+                let versions: Vec<String> = self.versions.iter().map(|v| v.to_string()).collect();
+                io::Error::new(io::ErrorKind::InvalidData, ProtocolError::UnsupportedVersion(format!("none in common, supported are {}", versions.join(" "))))
+            })?;
+
+        let capabilities = self.capabilities.iter()
+            .filter(|c| !matches!(c, Capability::Other(_)) && other.capabilities.contains(c))
+            .cloned()
+            .collect();
+
+        Ok(Hello { versions: vec![version], capabilities })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let versions: Vec<String> = self.versions.iter().map(|v| v.to_string()).collect();
+        let capabilities: Vec<String> = self.capabilities.iter().map(|c| c.to_string()).collect();
+
+        format!("versions {}\ncapabilities {}\n", versions.join(" "), capabilities.join(" ")).into_bytes()
+    }
+
+    /// Unknown versions are skipped, unknown capabilities are kept as `Capability::Other`.
+    pub fn decode(body: &[u8]) -> io::Result<Hello> {
+        let body = str::from_utf8(body).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "HELLO body isn't valid UTF-8."))?;
+
+        let mut versions = Vec::new();
+        let mut capabilities = Vec::new();
+
+        for line in body.lines() {
+            let mut parts = line.split_whitespace();
+
+            match parts.next() {
+                Some("versions") => versions.extend(parts.filter_map(RequestVersion::parse)),
+                Some("capabilities") => capabilities.extend(parts.map(Capability::parse)),
+                _ => (),
+            }
+        }
+
+        Ok(Hello { versions, capabilities })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_common_version() {
+        let server = Hello::new(&[RequestVersion::ZEROpTwo, RequestVersion::ZEROpThree], &[Capability::Deflate]);
+        let client = Hello::new(&[RequestVersion::ZEROpOne], &[Capability::Deflate]);
+
+        let err = server.negotiate(&client).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(err.get_ref().and_then(|e| e.downcast_ref::<ProtocolError>()), Some(ProtocolError::UnsupportedVersion(_))));
+        assert_eq!(crate::ErrorCode::from_io_error(&err), Some(crate::ErrorCode::VersionMismatch));
+    }
+
+    #[test]
+    fn negotiate_picks_highest_common_version_and_shared_capabilities() {
+        let server = Hello::new(&SUPPORTED_VERSIONS, &[Capability::Deflate, Capability::Delta, Capability::Resume, Capability::Checksum]);
+        let client = Hello::new(&[RequestVersion::ZEROpOne, RequestVersion::ZEROpTwo], &[Capability::Checksum, Capability::Deflate, Capability::Signature]);
+
+        let session = server.negotiate(&client).unwrap();
+        assert_eq!(session.get_versions(), [RequestVersion::ZEROpTwo]);
+        assert_eq!(session.get_capabilities(), [Capability::Deflate, Capability::Checksum]);
+        assert!(!session.has_capability(&Capability::Delta));
+        assert!(!session.has_capability(&Capability::Signature));
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let hello = Hello::new(&SUPPORTED_VERSIONS, &[
+            Capability::Deflate, Capability::Delta, Capability::Resume, Capability::Signature, Capability::Checksum,
+            Capability::Repositories, Capability::Releases, Capability::ManifestStream, Capability::LengthPrefixed, Capability::Metadata,
+        ]);
+
+        assert_eq!(hello.encode(), b"versions 0.1 0.2 0.3\ncapabilities deflate delta resume signature checksum repositories releases manifest-stream length-prefixed metadata\n");
+        assert_eq!(Hello::decode(&hello.encode()).unwrap(), hello);
+
+        let empty = Hello::new(&[RequestVersion::ZEROpOne], &[]);
+        assert_eq!(Hello::decode(&empty.encode()).unwrap(), empty);
+    }
+
+    #[test]
+    fn unknown_capabilities_are_ignored() {
+        let client = Hello::decode(b"versions 0.9 0.3\ncapabilities teleport deflate\nflavour vanilla\n").unwrap();
+        assert_eq!(client.get_versions(), [RequestVersion::ZEROpThree]);
+        assert_eq!(client.get_capabilities(), [Capability::Other("teleport".to_string()), Capability::Deflate]);
+
+        // Even if both sides name it, a capability neither understands isn't agreed on.
+        let server = Hello::new(&SUPPORTED_VERSIONS, &[Capability::Deflate, Capability::Other("teleport".to_string())]);
+        let session = server.negotiate(&client).unwrap();
+        assert_eq!(session.get_capabilities(), [Capability::Deflate]);
+
+        assert!(Hello::decode(b"\xff").is_err());
+    }
+}
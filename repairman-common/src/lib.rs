@@ -1,7 +1,69 @@
-#[derive(PartialEq, Eq, Debug, Hash)]
+mod codec;
+mod connection;
+mod delta;
+mod error;
+mod hello;
+mod manifest;
+mod paths;
+mod signing;
+
+pub use codec::*;
+pub use connection::*;
+pub use delta::*;
+pub use error::*;
+pub use hello::*;
+pub use manifest::*;
+pub use paths::*;
+pub use signing::*;
+
+#[derive(PartialEq, Eq, Debug, Hash, Clone)]
+pub enum FileKind {
+    Regular,
+    Directory,
+    /// Holds the link target as stored in the link, relative targets stay relative.
+    Symlink(String),
+}
+
+/// What a manifest with metadata carries besides path and hash. Directories and symlinks have an empty hash
+/// and a size of 0.
+#[derive(PartialEq, Eq, Debug, Hash, Clone)]
+pub struct FileMetadata {
+    kind: FileKind,
+    size: u64,
+    /// Unix permission bits, `None` if the server doesn't have any.
+    mode: Option<u32>,
+    /// Seconds since the Unix epoch.
+    modified: Option<u64>,
+}
+
+impl FileMetadata {
+    pub fn new(kind: FileKind, size: u64, mode: Option<u32>, modified: Option<u64>) -> FileMetadata {
+        FileMetadata { kind, size, mode, modified }
+    }
+
+    pub fn get_kind(&self) -> &FileKind {
+        &self.kind
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn get_mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    pub fn get_modified(&self) -> Option<u64> {
+        self.modified
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Hash, Clone)]
 pub struct HashedFile {
     path: String,
     hash: String,
+    /// `None` for manifests of servers that don't send metadata, the entry is a regular file then.
+    metadata: Option<FileMetadata>,
 }
 
 impl std::fmt::Display for HashedFile {
@@ -15,9 +77,15 @@ impl HashedFile {
         HashedFile {
             path: path.to_string(),
             hash: hash.to_string(),
+            metadata: None,
         }
     }
 
+    pub fn with_metadata(mut self, metadata: FileMetadata) -> HashedFile {
+        self.metadata = Some(metadata);
+        self
+    }
+
     pub fn get_path(&self) -> &str {
         &self.path
     }
@@ -25,22 +93,46 @@ impl HashedFile {
     pub fn get_hash(&self) -> &str {
         &self.hash
     }
+
+    pub fn get_metadata(&self) -> Option<&FileMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Only regular files have content, directories and symlinks are recreated from their metadata.
+    pub fn is_file(&self) -> bool {
+        self.metadata.as_ref().is_none_or(|metadata| metadata.kind == FileKind::Regular)
+    }
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
 pub enum RequestVersion {
     ZEROpOne,
+    ZEROpTwo,
+    ZEROpThree,
+}
+
+impl RequestVersion {
+    pub fn parse(version: &str) -> Option<RequestVersion> {
+        match version {
+            "0.1" => Some(RequestVersion::ZEROpOne),
+            "0.2" => Some(RequestVersion::ZEROpTwo),
+            "0.3" => Some(RequestVersion::ZEROpThree),
+            _ => None,
+        }
+    }
 }
 
 impl core::fmt::Display for RequestVersion {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RequestVersion::ZEROpOne => write!(f, "0.1"),
+            RequestVersion::ZEROpTwo => write!(f, "0.2"),
+            RequestVersion::ZEROpThree => write!(f, "0.3"),
         } 
     }
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum RequestType {
     GetHashes,
     GetFiles,
@@ -49,6 +141,58 @@ pub enum RequestType {
     Chunk,
     EndFile,
     Disconnect,
+    GetDelta,
+    GiveDelta,
+    DeltaCopy,
+    GetFilesFrom,
+    GetSignature,
+    GiveSignature,
+    Error,
+    Hello,
+}
+
+impl RequestType {
+    /// Type code used by the binary framing.
+    pub fn to_u8(self) -> u8 {
+        match self {
+            RequestType::GetHashes => 1,
+            RequestType::GetFiles => 2,
+            RequestType::GiveHashes => 3,
+            RequestType::GiveFiles => 4,
+            RequestType::Chunk => 5,
+            RequestType::EndFile => 6,
+            RequestType::Disconnect => 7,
+            RequestType::GetDelta => 8,
+            RequestType::GiveDelta => 9,
+            RequestType::DeltaCopy => 10,
+            RequestType::GetFilesFrom => 11,
+            RequestType::GetSignature => 12,
+            RequestType::GiveSignature => 13,
+            RequestType::Error => 14,
+            RequestType::Hello => 15,
+        }
+    }
+
+    pub fn from_u8(code: u8) -> Option<RequestType> {
+        match code {
+            1 => Some(RequestType::GetHashes),
+            2 => Some(RequestType::GetFiles),
+            3 => Some(RequestType::GiveHashes),
+            4 => Some(RequestType::GiveFiles),
+            5 => Some(RequestType::Chunk),
+            6 => Some(RequestType::EndFile),
+            7 => Some(RequestType::Disconnect),
+            8 => Some(RequestType::GetDelta),
+            9 => Some(RequestType::GiveDelta),
+            10 => Some(RequestType::DeltaCopy),
+            11 => Some(RequestType::GetFilesFrom),
+            12 => Some(RequestType::GetSignature),
+            13 => Some(RequestType::GiveSignature),
+            14 => Some(RequestType::Error),
+            15 => Some(RequestType::Hello),
+            _ => None,
+        }
+    }
 }
 
 impl core::fmt::Display for RequestType {
@@ -61,6 +205,14 @@ impl core::fmt::Display for RequestType {
             RequestType::Chunk => write!(f, "Chunk"),
             RequestType::EndFile => write!(f, "End File"),
             RequestType::Disconnect => write!(f, "Disconnect"),
+            RequestType::GetDelta => write!(f, "Get Delta"),
+            RequestType::GiveDelta => write!(f, "Give Delta"),
+            RequestType::DeltaCopy => write!(f, "Delta Copy"),
+            RequestType::GetFilesFrom => write!(f, "Get Files From"),
+            RequestType::GetSignature => write!(f, "Get Signature"),
+            RequestType::GiveSignature => write!(f, "Give Signature"),
+            RequestType::Error => write!(f, "Error"),
+            RequestType::Hello => write!(f, "Hello"),
         }
     }
 }
@@ -101,7 +253,7 @@ impl core::fmt::Display for Request {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum FileState {
     Present,
     Missing,
@@ -123,9 +275,7 @@ pub fn create_header(version: RequestVersion, reqeuest_type: RequestType, file_n
 
     let mut header_text = String::from("repairman ");
     
-    match version {
-        RequestVersion::ZEROpOne => header_text.push_str("0.1 "),
-    }
+    header_text.push_str(&format!("{version} "));
 
     match reqeuest_type {
         RequestType::GetHashes => header_text.push_str("GET-HASHES"),
@@ -135,6 +285,14 @@ pub fn create_header(version: RequestVersion, reqeuest_type: RequestType, file_n
         RequestType::Chunk => header_text.push_str("CHUNK"),
         RequestType::EndFile => header_text.push_str("END-FILE"),
         RequestType::Disconnect => header_text.push_str("DISCONNECT"),
+        RequestType::GetDelta => header_text.push_str("GET-DELTA"),
+        RequestType::GiveDelta => header_text.push_str("GIVE-DELTA"),
+        RequestType::DeltaCopy => header_text.push_str("DELTA-COPY"),
+        RequestType::GetFilesFrom => header_text.push_str("GET-FILES-FROM"),
+        RequestType::GetSignature => header_text.push_str("GET-SIGNATURE"),
+        RequestType::GiveSignature => header_text.push_str("GIVE-SIGNATURE"),
+        RequestType::Error => header_text.push_str("ERROR"),
+        RequestType::Hello => header_text.push_str("HELLO"),
     }
 
     let bytes = header_text.as_bytes();
@@ -148,12 +306,16 @@ pub fn create_header(version: RequestVersion, reqeuest_type: RequestType, file_n
     buffer
 }
 
-pub async fn async_parse_request(stream: &mut tokio::net::TcpStream) -> std::io::Result<Request> {
+pub async fn async_parse_request<S: tokio::io::AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<Request> {
     use tokio::io::AsyncReadExt;
 
     let mut header = [0u8; 64];
     stream.read_exact(&mut header).await?;
 
+    parse_header(&header)
+}
+
+pub fn parse_header(header: &[u8; 64]) -> std::io::Result<Request> {
     let body_size = u32::from_be_bytes(header[60..64].try_into().map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Couldn't read out body size from header."))?) as usize;
     let file_name_size = u32::from_be_bytes(header[56..60].try_into().map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Couldn't read out file name size from header."))?) as usize;
 
@@ -171,8 +333,9 @@ pub async fn async_parse_request(stream: &mut tokio::net::TcpStream) -> std::io:
     }
 
     let version = match sperate.next() {
-        Some("0.1") => RequestVersion::ZEROpOne,
-        _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Version in header is wrong.")),
+        Some(v) => RequestVersion::parse(v)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, ProtocolError::UnsupportedVersion(v.to_string())))?,
+        None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Version in header is missing.")),
     };
 
     let request_type = match sperate.next() {
@@ -180,11 +343,19 @@ pub async fn async_parse_request(stream: &mut tokio::net::TcpStream) -> std::io:
             match t {
                 "GIVE-HASHES" => RequestType::GiveHashes,
                 "GIVE-FILES" => RequestType::GiveFiles,
-                "GET-HASHES" => return Ok(Request::new(version, RequestType::GetHashes, 0, 0)),
+                "GET-HASHES" => RequestType::GetHashes,
                 "GET-FILES" => RequestType::GetFiles,
                 "CHUNK" => RequestType::Chunk,
                 "END-FILE" => RequestType::EndFile,
                 "DISCONNECT" => RequestType::Disconnect,
+                "GET-DELTA" => RequestType::GetDelta,
+                "GIVE-DELTA" => RequestType::GiveDelta,
+                "DELTA-COPY" => RequestType::DeltaCopy,
+                "GET-FILES-FROM" => RequestType::GetFilesFrom,
+                "GET-SIGNATURE" => RequestType::GetSignature,
+                "GIVE-SIGNATURE" => RequestType::GiveSignature,
+                "ERROR" => RequestType::Error,
+                "HELLO" => RequestType::Hello,
                 _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid request type was recieved.")),
             }
         }
@@ -0,0 +1,327 @@
+use std::io;
+
+use crate::{Capability, FileKind, FileMetadata, HashedFile, Hello};
+
+// Length-prefixed bodies, used once both sides have the `length-prefixed` capability. All integers big endian.
+//   manifest entry: u32 path length, path, u16 hash length, hash
+//     with the `metadata` capability followed by: u8 kind (0 regular, 1 directory, 2 symlink),
+//     u8 flags (1 mode set, 2 modification time set), u64 size, u32 mode, u64 modification time,
+//     u32 link target length, link target
+//   GET-FILES name: u32 name length, name
+// Names can hold spaces and newlines this way, the text bodies of older peers split on them.
+
+/// How GIVE-HASHES bodies and GET-FILES names are encoded on a connection.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ManifestFormat {
+    /// "path hash" lines, names are separated by newlines.
+    Text,
+    LengthPrefixed,
+    /// Length-prefixed with kind, size, mode and modification time, the only one with directories and symlinks.
+    Metadata,
+}
+
+impl ManifestFormat {
+    pub fn negotiated(session: &Hello) -> ManifestFormat {
+        match (session.has_capability(&Capability::LengthPrefixed), session.has_capability(&Capability::Metadata)) {
+            (true, true) => ManifestFormat::Metadata,
+            (true, false) => ManifestFormat::LengthPrefixed,
+            _ => ManifestFormat::Text,
+        }
+    }
+}
+
+const KIND_REGULAR: u8 = 0;
+const KIND_DIRECTORY: u8 = 1;
+const KIND_SYMLINK: u8 = 2;
+
+const FLAG_MODE: u8 = 0x01;
+const FLAG_MODIFIED: u8 = 0x02;
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn read_len(data: &[u8], pos: usize, size: usize) -> io::Result<usize> {
+    let bytes = data.get(pos..pos + size).ok_or_else(|| invalid("Length-prefixed body ends early."))?;
+
+    Ok(bytes.iter().fold(0usize, |len, b| (len << 8) | usize::from(*b)))
+}
+
+fn read_u64(data: &[u8], pos: usize) -> io::Result<u64> {
+    let bytes = data.get(pos..pos + 8).ok_or_else(|| invalid("Length-prefixed body ends early."))?;
+
+    Ok(bytes.iter().fold(0u64, |value, b| (value << 8) | u64::from(*b)))
+}
+
+fn read_str(data: &[u8], pos: usize, len: usize) -> io::Result<&str> {
+    let bytes = data.get(pos..pos + len).ok_or_else(|| invalid("Length-prefixed body ends early."))?;
+
+    str::from_utf8(bytes).map_err(|_| invalid("Length-prefixed body contains a name that isn't valid UTF-8."))
+}
+
+fn put_str_u32(buffer: &mut Vec<u8>, value: &str, what: &str) -> io::Result<()> {
+    let len = u32::try_from(value.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{what} is too long for the manifest.")))?;
+
+    buffer.extend_from_slice(&len.to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+
+    Ok(())
+}
+
+/// With `metadata` the entry carries kind, size, mode and modification time, entries without any are sent as regular files.
+pub fn encode_manifest_entry(buffer: &mut Vec<u8>, file: &HashedFile, metadata: bool) -> io::Result<()> {
+    let hash_len = u16::try_from(file.get_hash().len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Hash is too long for the manifest."))?;
+
+    put_str_u32(buffer, file.get_path(), "Path")?;
+    buffer.extend_from_slice(&hash_len.to_be_bytes());
+    buffer.extend_from_slice(file.get_hash().as_bytes());
+
+    if !metadata {
+        return Ok(());
+    }
+
+    let regular = FileMetadata::new(FileKind::Regular, 0, None, None);
+    let file_metadata = file.get_metadata().unwrap_or(&regular);
+
+    let (kind, target) = match file_metadata.get_kind() {
+        FileKind::Regular => (KIND_REGULAR, ""),
+        FileKind::Directory => (KIND_DIRECTORY, ""),
+        FileKind::Symlink(target) => (KIND_SYMLINK, target.as_str()),
+    };
+
+    let mut flags = 0;
+    if file_metadata.get_mode().is_some() {
+        flags |= FLAG_MODE;
+    }
+    if file_metadata.get_modified().is_some() {
+        flags |= FLAG_MODIFIED;
+    }
+
+    buffer.push(kind);
+    buffer.push(flags);
+    buffer.extend_from_slice(&file_metadata.get_size().to_be_bytes());
+    buffer.extend_from_slice(&file_metadata.get_mode().unwrap_or(0).to_be_bytes());
+    buffer.extend_from_slice(&file_metadata.get_modified().unwrap_or(0).to_be_bytes());
+    put_str_u32(buffer, target, "Link target")
+}
+
+/// Size of the manifest entry at the start of `data`.
+pub fn manifest_entry_len(data: &[u8], metadata: bool) -> io::Result<usize> {
+    let path_len = read_len(data, 0, 4)?;
+    let hash_len = read_len(data, 4 + path_len, 2)?;
+
+    let mut len = 4 + path_len + 2 + hash_len;
+
+    if metadata {
+        let target_len = read_len(data, len + 22, 4)?;
+        len += 26 + target_len;
+    }
+
+    if data.len() < len {
+        return Err(invalid("Length-prefixed body ends early."));
+    }
+
+    Ok(len)
+}
+
+/// Appends the entries of `body` to `files`, `body` has to end on an entry.
+pub fn decode_manifest(body: &[u8], metadata: bool, files: &mut Vec<HashedFile>) -> io::Result<()> {
+    let mut pos = 0;
+
+    while pos < body.len() {
+        let path_len = read_len(body, pos, 4)?;
+        let path = read_str(body, pos + 4, path_len)?;
+        pos += 4 + path_len;
+
+        let hash_len = read_len(body, pos, 2)?;
+        let hash = read_str(body, pos + 2, hash_len)?;
+        pos += 2 + hash_len;
+
+        let mut file = HashedFile::new(path, hash);
+
+        if metadata {
+            let kind = read_len(body, pos, 1)? as u8;
+            let flags = read_len(body, pos + 1, 1)? as u8;
+            let size = read_u64(body, pos + 2)?;
+            let mode = read_len(body, pos + 10, 4)? as u32;
+            let modified = read_u64(body, pos + 14)?;
+            let target_len = read_len(body, pos + 22, 4)?;
+            let target = read_str(body, pos + 26, target_len)?;
+            pos += 26 + target_len;
+
+            let kind = match kind {
+                KIND_REGULAR => FileKind::Regular,
+                KIND_DIRECTORY => FileKind::Directory,
+                KIND_SYMLINK => FileKind::Symlink(target.to_string()),
+                _ => return Err(invalid("Manifest entry has an unknown kind.")),
+            };
+
+            let mode = (flags & FLAG_MODE != 0).then_some(mode);
+            let modified = (flags & FLAG_MODIFIED != 0).then_some(modified);
+
+            file = file.with_metadata(FileMetadata::new(kind, size, mode, modified));
+        }
+
+        files.push(file);
+    }
+
+    Ok(())
+}
+
+pub fn encode_names<'a, I: IntoIterator<Item = &'a str>>(names: I) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+
+    for name in names {
+        let len = u32::try_from(name.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Name is too long for a request."))?;
+
+        body.extend_from_slice(&len.to_be_bytes());
+        body.extend_from_slice(name.as_bytes());
+    }
+
+    Ok(body)
+}
+
+pub fn decode_names(body: &[u8]) -> io::Result<Vec<&str>> {
+    let mut names = Vec::new();
+    let mut pos = 0;
+
+    while pos < body.len() {
+        let len = read_len(body, pos, 4)?;
+        names.push(read_str(body, pos + 4, len)?);
+        pos += 4 + len;
+    }
+
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(files: &[HashedFile], metadata: bool) -> Vec<u8> {
+        let mut body = Vec::new();
+        for file in files {
+            encode_manifest_entry(&mut body, file, metadata).unwrap();
+        }
+        body
+    }
+
+    fn sample() -> Vec<HashedFile> {
+        vec![
+            HashedFile::new("a.txt", "aaaa"),
+            HashedFile::new("dir/with space\nand newline", "bbbb"),
+        ]
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let files = sample();
+        let body = encode(&files, false);
+
+        let mut decoded = Vec::new();
+        decode_manifest(&body, false, &mut decoded).unwrap();
+
+        assert_eq!(decoded, files);
+    }
+
+    #[test]
+    fn entry_len_splits_at_entries() {
+        let body = encode(&sample(), false);
+
+        let first = manifest_entry_len(&body, false).unwrap();
+        assert_eq!(first, 4 + 5 + 2 + 4);
+        assert_eq!(manifest_entry_len(&body[first..], false).unwrap(), body.len() - first);
+    }
+
+    #[test]
+    fn truncated_entries_are_refused() {
+        let body = encode(&sample(), false);
+
+        let first = manifest_entry_len(&body, false).unwrap();
+
+        for len in (1..body.len()).filter(|len| *len != first) {
+            assert!(decode_manifest(&body[..len], false, &mut Vec::new()).is_err(), "length {len}");
+        }
+    }
+
+    #[test]
+    fn huge_lengths_are_refused() {
+        let mut body = u32::MAX.to_be_bytes().to_vec();
+        body.extend_from_slice(b"abc");
+
+        assert!(manifest_entry_len(&body, false).is_err());
+        assert!(decode_manifest(&body, false, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn invalid_utf8_is_refused() {
+        let mut body = 1u32.to_be_bytes().to_vec();
+        body.push(0xff);
+        body.extend_from_slice(&0u16.to_be_bytes());
+
+        assert_eq!(decode_manifest(&body, false, &mut Vec::new()).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn with_metadata() -> Vec<HashedFile> {
+        vec![
+            HashedFile::new("bin/run.sh", "aaaa").with_metadata(FileMetadata::new(FileKind::Regular, 12, Some(0o755), Some(1_700_000_000))),
+            HashedFile::new("empty", "").with_metadata(FileMetadata::new(FileKind::Directory, 0, Some(0o700), None)),
+            HashedFile::new("bin/link", "").with_metadata(FileMetadata::new(FileKind::Symlink("../lib/x".to_string()), 0, None, Some(5))),
+        ]
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let files = with_metadata();
+        let body = encode(&files, true);
+
+        let mut decoded = Vec::new();
+        decode_manifest(&body, true, &mut decoded).unwrap();
+
+        assert_eq!(decoded, files);
+
+        let mut end = 0;
+        for _ in &files {
+            end += manifest_entry_len(&body[end..], true).unwrap();
+        }
+        assert_eq!(end, body.len());
+    }
+
+    #[test]
+    fn entries_without_metadata_are_sent_as_regular_files() {
+        let body = encode(&[HashedFile::new("a", "aaaa")], true);
+
+        let mut decoded = Vec::new();
+        decode_manifest(&body, true, &mut decoded).unwrap();
+
+        assert_eq!(decoded[0].get_metadata(), Some(&FileMetadata::new(FileKind::Regular, 0, None, None)));
+        assert!(decoded[0].is_file());
+    }
+
+    #[test]
+    fn truncated_metadata_is_refused() {
+        let body = encode(&with_metadata()[2..], true);
+
+        for len in 1..body.len() {
+            assert!(decode_manifest(&body[..len], true, &mut Vec::new()).is_err(), "length {len}");
+            assert!(manifest_entry_len(&body[..len], true).is_err(), "length {len}");
+        }
+    }
+
+    #[test]
+    fn unknown_kind_is_refused() {
+        let mut body = encode(&[HashedFile::new("a", "aaaa")], true);
+        body[4 + 1 + 2 + 4] = 9;
+
+        assert_eq!(decode_manifest(&body, true, &mut Vec::new()).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let names = ["a.txt", "with space", "new\nline", ""];
+        let body = encode_names(names).unwrap();
+
+        assert_eq!(decode_names(&body).unwrap(), names);
+        assert!(decode_names(&body[..body.len() - 1]).is_err());
+    }
+}
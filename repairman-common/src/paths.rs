@@ -0,0 +1,180 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum PathError {
+    Empty,
+    Absolute(String),
+    ParentDir(String),
+    InvalidCharacter(String),
+    NotNormalized(String),
+}
+
+impl core::fmt::Display for PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathError::Empty => write!(f, "Path is empty"),
+            PathError::Absolute(p) => write!(f, "Path is absolute: {p:?}"),
+            PathError::ParentDir(p) => write!(f, "Path leaves its directory: {p:?}"),
+            PathError::InvalidCharacter(p) => write!(f, "Path contains an invalid character: {p:?}"),
+            PathError::NotNormalized(p) => write!(f, "Path isn't written as name/name/...: {p:?}"),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl From<PathError> for io::Error {
+    fn from(err: PathError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Turns a path received over the network into a relative path without any `..`, root or drive prefix.
+pub fn relative_path(name: &str) -> Result<PathBuf, PathError> {
+    if name.contains('\0') {
+        return Err(PathError::InvalidCharacter(name.to_string()));
+    }
+
+    let mut path = PathBuf::new();
+
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => (),
+            Component::ParentDir => return Err(PathError::ParentDir(name.to_string())),
+            Component::RootDir | Component::Prefix(_) => return Err(PathError::Absolute(name.to_string())),
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err(PathError::Empty);
+    }
+
+    Ok(path)
+}
+
+/// Manifest paths are relative to the served directory, with `/` between their parts and no empty, `.` or `..` part,
+/// so every client lays the files out the same way. A `\` would be a separator on Windows, it's refused everywhere.
+pub fn check_manifest_path(name: &str) -> Result<(), PathError> {
+    relative_path(name)?;
+
+    if name.contains('\\') {
+        return Err(PathError::InvalidCharacter(name.to_string()));
+    }
+
+    if name.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+        return Err(PathError::NotNormalized(name.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Checks that the symlink at the manifest path `link` points to `target` inside the served directory, so nothing
+/// written through it later can land outside of it. `..` is only allowed at the start of the target, where it climbs
+/// real directories as long as no parent of the link is a symlink itself.
+pub fn check_link_target(link: &str, target: &str) -> Result<(), PathError> {
+    if target.is_empty() {
+        return Err(PathError::Empty);
+    }
+
+    if target.contains('\0') {
+        return Err(PathError::InvalidCharacter(target.to_string()));
+    }
+
+    // Directories above the link's own, counted from the served root.
+    let mut depth = link.split('/').count() - 1;
+    let mut descended = false;
+
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(_) => descended = true,
+            Component::CurDir => (),
+            Component::ParentDir if !descended => {
+                depth = depth.checked_sub(1).ok_or_else(|| PathError::ParentDir(target.to_string()))?;
+            },
+            Component::ParentDir => return Err(PathError::ParentDir(target.to_string())),
+            Component::RootDir | Component::Prefix(_) => return Err(PathError::Absolute(target.to_string())),
+        }
+    }
+
+    Ok(())
+}
+
+/// Joins an untrusted relative path onto `root`, the result always stays inside of it.
+pub fn join_relative(root: &Path, name: &str) -> Result<PathBuf, PathError> {
+    Ok(root.join(relative_path(name)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_path_keeps_plain_names() {
+        assert_eq!(relative_path("a/b.txt"), Ok(PathBuf::from("a/b.txt")));
+        assert_eq!(relative_path("./a"), Ok(PathBuf::from("a")));
+        assert_eq!(relative_path("a//b"), Ok(PathBuf::from("a/b")));
+    }
+
+    #[test]
+    fn relative_path_refuses_hostile_names() {
+        assert_eq!(relative_path(""), Err(PathError::Empty));
+        assert_eq!(relative_path("."), Err(PathError::Empty));
+        assert_eq!(relative_path("../x"), Err(PathError::ParentDir("../x".to_string())));
+        assert_eq!(relative_path("a/../../x"), Err(PathError::ParentDir("a/../../x".to_string())));
+        assert_eq!(relative_path("/etc/passwd"), Err(PathError::Absolute("/etc/passwd".to_string())));
+        assert_eq!(relative_path("a\0b"), Err(PathError::InvalidCharacter("a\0b".to_string())));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn relative_path_refuses_drive_prefixes() {
+        assert_eq!(relative_path("C:\\x"), Err(PathError::Absolute("C:\\x".to_string())));
+        assert_eq!(relative_path("\\\\server\\share\\x"), Err(PathError::Absolute("\\\\server\\share\\x".to_string())));
+    }
+
+    #[test]
+    fn join_relative_stays_inside_root() {
+        let root = Path::new("root");
+
+        assert_eq!(join_relative(root, "a/b").unwrap(), root.join("a").join("b"));
+        assert!(join_relative(root, "../x").is_err());
+        assert!(join_relative(root, "a/../../x").is_err());
+        assert!(join_relative(root, "/etc/passwd").is_err());
+        assert!(join_relative(root, "a\0").is_err());
+    }
+
+    #[test]
+    fn manifest_paths_are_normalized() {
+        assert_eq!(check_manifest_path("a/b c/d.txt"), Ok(()));
+        assert_eq!(check_manifest_path("a//b"), Err(PathError::NotNormalized("a//b".to_string())));
+        assert_eq!(check_manifest_path("./a"), Err(PathError::NotNormalized("./a".to_string())));
+        assert_eq!(check_manifest_path("a/"), Err(PathError::NotNormalized("a/".to_string())));
+        assert_eq!(check_manifest_path("C:\\x"), Err(PathError::InvalidCharacter("C:\\x".to_string())));
+        assert!(check_manifest_path("../x").is_err());
+        assert!(check_manifest_path("/etc/passwd").is_err());
+        assert!(check_manifest_path("a\0b").is_err());
+    }
+
+    #[test]
+    fn link_targets_stay_inside_root() {
+        assert_eq!(check_link_target("a", "b"), Ok(()));
+        assert_eq!(check_link_target("bin/link", "../lib/x.so"), Ok(()));
+        assert_eq!(check_link_target("a/b/link", "../../c"), Ok(()));
+        assert_eq!(check_link_target("a/link", "./b/c"), Ok(()));
+    }
+
+    #[test]
+    fn link_targets_climbing_out_are_refused() {
+        assert_eq!(check_link_target("link", "../x"), Err(PathError::ParentDir("../x".to_string())));
+        assert_eq!(check_link_target("a/link", "../../x"), Err(PathError::ParentDir("../../x".to_string())));
+        assert_eq!(check_link_target("a/b/link", "c/../../../x"), Err(PathError::ParentDir("c/../../../x".to_string())));
+        assert_eq!(check_link_target("a/link", "b/../c"), Err(PathError::ParentDir("b/../c".to_string())));
+        assert_eq!(check_link_target("link", "/etc/passwd"), Err(PathError::Absolute("/etc/passwd".to_string())));
+        assert_eq!(check_link_target("link", "a\0"), Err(PathError::InvalidCharacter("a\0".to_string())));
+        assert_eq!(check_link_target("link", ""), Err(PathError::Empty));
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
-    cell::RefCell, collections::HashMap, fs, io::{self, BufRead, BufReader, Read, Write}, path::{Path, PathBuf}
+    cell::RefCell, collections::{HashMap, HashSet}, fs, io::{self, Read, Write}, path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
 };
 
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
@@ -11,196 +12,496 @@ use file_hashing::get_hash_file;
 
 use repairman_common::*;
 
-pub fn parse_cache(path: &Path, files: &[HashedFile]) -> io::Result<HashMap<String, String>> {
-    let inventory_file = path.join(Path::new("inventory.compmeta"));
+use crate::inventory::*;
+use crate::server::{Emit, ServerEvent};
+use crate::releases::{parse_manifest, release_ids, read_release};
 
-    if !inventory_file.exists() {
-        return create_cache(path, files);
+// Layout of a cache directory, shared by every repository of a server (and by other servers pointed at it):
+//   objects/ab/cdef...                  compressed content, named after the hash of the uncompressed file
+//   inventory.compmeta                  hash and compressed hash per file of the unnamed repository
+//   repositories/<name>/inventory.compmeta
+// Releases and tags sit next to the inventory they belong to.
+
+pub fn check_hex(value: &str, what: &str) -> io::Result<()> {
+    if value.len() < 3 || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{what} {value:?} isn't a valid hash.")));
     }
 
-    let mut inv_map: HashMap<HashedFile, String> = HashMap::new();
+    Ok(())
+}
 
-    let meta_file_handle = fs::File::open(inventory_file)?;
-    let meta_file_handle = BufReader::new(meta_file_handle);
-    let mut segments = meta_file_handle.split(b'\0').map(|seg| {
-        seg.and_then(|bytes| {
-            String::from_utf8(bytes).map_err(|_e| io::Error::new(io::ErrorKind::InvalidData, "Unable to read out segment of cache metadata file."))
-        })
-    });
+/// Compressed content of every file with the hash `hash`, no matter under which path or in which repository.
+pub fn object_path(cache: &Path, hash: &str) -> io::Result<PathBuf> {
+    check_hex(hash, "File hash")?;
 
-    while let Some(path_res) = segments.next() {
-        let path = path_res?;
+    Ok(cache.join("objects").join(&hash[..2]).join(&hash[2..]))
+}
 
-        let origin_file_hash = segments.next()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unable to trun a origin file hash into a string."))??;
+/// Directory with the inventory, releases and tags of a repository.
+pub fn repository_dir(cache: &Path, repository: &str) -> PathBuf {
+    if repository.is_empty() {
+        cache.to_path_buf()
+    } else {
+        cache.join("repositories").join(repository)
+    }
+}
 
-        let compressed_file_hash = segments.next()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unable to trun a compressed file hash into a string."))??;
+pub fn object_path_str(cache: &Path, hash: &str) -> io::Result<String> {
+    object_path(cache, hash)?.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unable to turn object path into a string."))
+}
 
-        inv_map.insert(HashedFile::new(&path, &origin_file_hash), compressed_file_hash);
-    }
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
 
-    let mut buffer = vec![0u8; 8192];
-    let mut cache_was_invalid = false;
-    let mut paths_map = HashMap::with_capacity(files.len());
+fn hash_object(object: &Path) -> io::Result<String> {
+    let mut hasher = Blake2s256::new();
 
-    for file in files {
-        let mut file_has_to_be_redone = false;
-        
-        let path_to_cmp = path.join("files").join(file.get_path());
-        let mut os_file_path = path_to_cmp.into_os_string();
-        os_file_path.push(".comp");
+    get_hash_file(object, &mut hasher)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to hash {:?}: {}", object, e)))
+}
 
-        let path_to_cmp = PathBuf::from(os_file_path);
+/// Compresses `origin` into `object` and returns the hash of the compressed file. The object is written next to
+/// its final place and renamed over it, so nobody ever reads half an object.
+fn compress_object(origin: &Path, object: &Path, buffer: &mut [u8]) -> io::Result<String> {
+    if let Some(parent) = object.parent() {
+        fs::create_dir_all(parent)?;
+    }
 
+    // Two repositories can bring the same content at once, each writes its own temporary file.
+    let mut os_tmp_object = object.to_path_buf().into_os_string();
+    os_tmp_object.push(format!(".{}.tmp", TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let tmp_object = PathBuf::from(os_tmp_object);
 
-        let compressed_file_exists = path_to_cmp.exists();
+    let mut origin_file_handle = fs::File::open(origin)?;
+    let mut encoder = DeflateEncoder::new(fs::File::create(&tmp_object)?, Compression::fast());
 
-        let path_to_cmp = path_to_cmp.to_str().unwrap();
+    loop {
+        let n = origin_file_handle.read(buffer)?;
+        if n == 0 { break; };
 
-        paths_map.insert(file.get_path().to_string(), path_to_cmp.to_string());
+        encoder.write_all(&buffer[..n])?;
+    }
 
-        let hashedfile_to_cmp = HashedFile::new(path_to_cmp, file.get_hash());
+    encoder.finish()?;
+    fs::rename(&tmp_object, object)?;
 
-        if let Some((hashedfile, compressed_hash)) = inv_map.get_key_value(&hashedfile_to_cmp) &&
-                    hashedfile.get_hash() == file.get_hash() &&
-                    compressed_file_exists {
+    hash_object(object)
+}
 
-            let mut hasher = Blake2s256::new();
+/// A damaged inventory only costs a rebuild, the objects it pointed to are found again by their hash.
+fn load_inventory(inventory_file: &Path, emit: Emit) -> io::Result<Inventory> {
+    match read_inventory(inventory_file) {
+        Ok(inventory) => Ok(inventory),
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+            emit(ServerEvent::CacheInvalid { reason: &err.to_string() });
+            Ok(Inventory::new())
+        },
+        Err(err) => Err(err),
+    }
+}
 
-            let actual_compressed_files_hash = get_hash_file(path_to_cmp, &mut hasher)
-                .map_err(|e| io::Error::new(e.kind(), format!("Failed to hash {:?}: {}", path_to_cmp, e)))?;
+/// The entry of `file` if it still has the hash the inventory knows it with.
+fn inventory_entry<'a>(inventory: &'a Inventory, file: &HashedFile) -> Option<&'a InventoryEntry> {
+    inventory.get(file.get_path()).filter(|entry| entry.hash == file.get_hash())
+}
 
-            if actual_compressed_files_hash.as_str() != compressed_hash.as_str() {
-                file_has_to_be_redone = true;
-            }
-        } else {
-            file_has_to_be_redone = true;
-        }
+/// Writes the inventory and returns the map from served path to object.
+fn store_inventory(cache: &Path, dir: &Path, files: &[HashedFile], compressed_hashes: &HashMap<&str, String>) -> io::Result<HashMap<String, String>> {
+    fs::create_dir_all(dir)?;
 
-        if file_has_to_be_redone {
-            cache_was_invalid = true;
+    let mut entries = Vec::with_capacity(files.len());
+    let mut paths_map = HashMap::with_capacity(files.len());
+    let mut stamps: HashMap<&str, ObjectStamp> = HashMap::with_capacity(compressed_hashes.len());
+
+    for file in files.iter().filter(|f| f.is_file()) {
+        let compressed_hash = compressed_hashes.get(file.get_hash())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("No object for {}.", file.get_path())))?;
+
+        let stamp = match stamps.get(file.get_hash()) {
+            Some(stamp) => *stamp,
+            None => {
+                let stamp = ObjectStamp::read(&object_path(cache, file.get_hash())?)?;
+                stamps.insert(file.get_hash(), stamp);
+                stamp
+            },
+        };
 
-            let mut origin_file_handle = fs::File::open(file.get_path())?;
-            let compressed_file_handle = fs::File::create(path_to_cmp)?;
-            let mut encoder = DeflateEncoder::new(compressed_file_handle, Compression::fast());
+        entries.push((file.get_path(), file.get_hash(), compressed_hash.as_str(), stamp));
+        paths_map.insert(file.get_path().to_string(), object_path_str(cache, file.get_hash())?);
+    }
 
-            loop {
-                let n = origin_file_handle.read(&mut buffer)?;
-                if n == 0 { break; };
+    write_inventory(&dir.join(Path::new("inventory.compmeta")), entries.into_iter())?;
 
-                encoder.write_all(&buffer[..n])?;
-            }
+    Ok(paths_map)
+}
 
-            encoder.finish()?;
-        }
+/// Objects whose size, modification time and inode match the inventory are trusted, with `verify` every object
+/// is hashed again and compared to the inventory.
+pub fn parse_cache(cache: &Path, dir: &Path, root: &Path, files: &[HashedFile], verify: bool, emit: Emit) -> io::Result<HashMap<String, String>> {
+    let inventory_file = dir.join(Path::new("inventory.compmeta"));
+
+    if !inventory_file.exists() {
+        return create_cache(cache, dir, root, files, emit);
     }
 
-    if cache_was_invalid {
-        println!("Cache was invalid, redoing the metadata file.");
-        let mut metadata = String::with_capacity(264 * files.len());
+    let inv_map = load_inventory(&inventory_file, emit)?;
 
-        let results: Vec<io::Result<String>> = files.par_iter().map(|f| {
-            let path = path.join("files").join(f.get_path());
-            let mut os_file_path = path.into_os_string();
-            os_file_path.push(".comp");
+    // Files with the same content share their object, it only has to be checked once.
+    let unique = unique_contents(files, &HashMap::new());
 
-            let path = PathBuf::from(os_file_path);
+    let checked: Vec<io::Result<(&str, Option<String>)>> = unique.par_iter().map(|file| {
+        let object = object_path(cache, file.get_hash())?;
 
-            let path = path.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unable to turn path into a string, for creation of metadata file."))?;
+        if !object.exists() {
+            return Ok((file.get_hash(), None));
+        }
 
-            let mut hasher = Blake2s256::new();
+        let compressed_hash = match inventory_entry(&inv_map, file) {
+            Some(entry) if !verify && entry.stamp == Some(ObjectStamp::read(&object)?) => Some(entry.compressed_hash.clone()),
+            Some(entry) => Some(hash_object(&object)?).filter(|actual| *actual == entry.compressed_hash),
+            // Objects are only ever renamed into place, one another repository or release left here is complete.
+            None => Some(hash_object(&object)?),
+        };
 
-            let current_comp_hash = get_hash_file(path, &mut hasher)
-                .map_err(|e| io::Error::new(e.kind(), format!("Failed to hash {:?}: {}", &path, e)))?;
+        Ok((file.get_hash(), compressed_hash))
+    }).collect();
 
-            Ok(format!("{}\0{}\0{}\0", path, f.get_hash(), current_comp_hash))
-        }).collect();
+    let mut cache_was_invalid = files.iter().any(|f| f.is_file() && inventory_entry(&inv_map, f).is_none());
+    let mut known: HashMap<&str, String> = HashMap::with_capacity(files.len());
 
-        for line in results {
-            let line = line?;
-            metadata.push_str(&line);
+    for entry in checked {
+        match entry? {
+            (hash, Some(compressed_hash)) => { known.insert(hash, compressed_hash); },
+            (_, None) => cache_was_invalid = true,
         }
+    }
+
+    if cache_was_invalid {
+        emit(ServerEvent::CacheInvalid { reason: "files or objects changed since the inventory was written" });
+    }
+
+    let compressed_hashes = store_objects(cache, root, files, &known, true, emit)?;
+
+    store_inventory(cache, dir, files, &compressed_hashes)
+}
 
-        fs::write(path.join(Path::new("inventory.compmeta")), metadata)?;
+fn unique_contents<'a>(files: &'a [HashedFile], known: &HashMap<&str, String>) -> Vec<&'a HashedFile> {
+    let mut unique: HashMap<&str, &HashedFile> = HashMap::with_capacity(files.len());
+    for file in files.iter().filter(|f| f.is_file()) {
+        if !known.contains_key(file.get_hash()) {
+            unique.entry(file.get_hash()).or_insert(file);
+        }
     }
 
-    Ok(paths_map)
+    unique.into_values().collect()
 }
 
 thread_local! {
     static THEAD_BUFFER: RefCell<Vec<u8>> = RefCell::new(vec![0u8; 8192]);
 }
 
-pub fn create_cache(path: &Path, files: &[HashedFile]) -> io::Result<HashMap<String, String>> {
-    fs::create_dir_all(path)?;
+/// Compresses the files below `root` whose object doesn't exist yet, in parallel and each content only once. With `overwrite`
+/// every file that isn't `known` is compressed again, even over an existing object.
+/// Returns the compressed hash of every object `files` needs.
+fn store_objects<'a>(cache: &Path, root: &Path, files: &'a [HashedFile], known: &HashMap<&'a str, String>, overwrite: bool, emit: Emit) -> io::Result<HashMap<&'a str, String>> {
+    let unique = unique_contents(files, known);
 
-    let cache_parts: Vec<io::Result<ChachePart>> = files.par_iter().map(|f| {
-        let mut file_handle = fs::File::open(f.get_path())?;
-        let path = path.join(Path::new("files")).join(f.get_path());
+    let total = unique.len();
+    let step = total.div_ceil(10).max(1);
+    let done = AtomicUsize::new(0);
+
+    if total > 0 {
+        emit(ServerEvent::CacheProgress { done: 0, total });
+    }
 
-        if let Some(parent) = path.parent() && !parent.exists() {
-            fs::create_dir_all(parent)?;
+    let stored: Vec<io::Result<(&str, String)>> = unique.par_iter().map(|f| {
+        let object = object_path(cache, f.get_hash())?;
+
+        let compressed_hash = if object.exists() && !overwrite {
+            hash_object(&object)?
+        } else {
+            THEAD_BUFFER.with(|buffer| compress_object(&root.join(f.get_path()), &object, &mut buffer.borrow_mut()))?
+        };
+
+        let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+        if done.is_multiple_of(step) && done < total {
+            emit(ServerEvent::CacheProgress { done, total });
         }
 
-        let mut os_file_path = path.into_os_string();
-        os_file_path.push(".comp");
+        Ok((f.get_hash(), compressed_hash))
+    }).collect();
 
-        let path = PathBuf::from(os_file_path);
+    let mut compressed_hashes = known.clone();
 
-        let compressed_file = fs::File::create(&path)?;
-        let mut encoder = DeflateEncoder::new(compressed_file, Compression::fast());
+    for entry in stored {
+        let (hash, compressed_hash) = entry?;
+        compressed_hashes.insert(hash, compressed_hash);
+    }
 
-        THEAD_BUFFER.with(|buffer| -> io::Result<()> {
-            let mut buffer = buffer.borrow_mut();
-            loop {
-                let n = file_handle.read(&mut buffer)?;
-                if n == 0 { break; };
+    Ok(compressed_hashes)
+}
 
-                encoder.write_all(&buffer[..n])?;
-            }
-            Ok(())
-        })?;
+pub fn create_cache(cache: &Path, dir: &Path, root: &Path, files: &[HashedFile], emit: Emit) -> io::Result<HashMap<String, String>> {
+    fs::create_dir_all(cache)?;
 
-        encoder.finish()?;
+    let compressed_hashes = store_objects(cache, root, files, &HashMap::new(), false, emit)?;
 
-        let mut hasher = Blake2s256::new();
+    store_inventory(cache, dir, files, &compressed_hashes)
+}
 
-        let compressed_file_hash = get_hash_file(&path, &mut hasher)
-            .map_err(|e| io::Error::new(e.kind(), format!("Failed to hash {:?}: {}", &path, e)))?;
+/// Brings the cache in line with a rescanned file list. Objects of unchanged entries whose stamp still matches
+/// aren't hashed again, only new content gets compressed.
+pub fn update_cache(cache: &Path, dir: &Path, root: &Path, files: &[HashedFile], emit: Emit) -> io::Result<HashMap<String, String>> {
+    let inventory_file = dir.join(Path::new("inventory.compmeta"));
 
-        let path = match path.to_str() {
-            Some(p) => p,
-            None => return Err(io::Error::new(io::ErrorKind::AddrInUse, "")),
-        };
+    if !inventory_file.exists() {
+        return create_cache(cache, dir, root, files, emit);
+    }
 
-        Ok(ChachePart::new(format!("{}\0{}\0{}\0", path, f.get_hash(), compressed_file_hash), f.get_path().to_string(), path.to_string()))
-    }).collect();
+    let inv_map = load_inventory(&inventory_file, emit)?;
 
-    let mut metadata = String::with_capacity(264 * cache_parts.len());
-    let mut paths_map = HashMap::with_capacity(cache_parts.len());
+    let mut known: HashMap<&str, String> = HashMap::with_capacity(files.len());
+    for file in files.iter().filter(|f| f.is_file()) {
+        if let Some(entry) = inventory_entry(&inv_map, file) && entry.stamp.is_some() && entry.stamp == ObjectStamp::read(&object_path(cache, file.get_hash())?).ok() {
+            known.insert(file.get_hash(), entry.compressed_hash.clone());
+        }
+    }
 
-    for part in cache_parts {
-        let part = part?;
-        metadata.push_str(&part.compmeta_line);
-        paths_map.insert(part.uncompressed_path, part.compressed_path);
+    let compressed_hashes = store_objects(cache, root, files, &known, false, emit)?;
+
+    store_inventory(cache, dir, files, &compressed_hashes)
+}
+
+/// Hashes of every object an inventory or a stored release in the cache still refers to.
+fn referenced_objects(cache: &Path) -> io::Result<HashSet<String>> {
+    let mut dirs = vec![cache.to_path_buf()];
+
+    let repositories = cache.join("repositories");
+    if repositories.exists() {
+        for entry in fs::read_dir(repositories)? {
+            dirs.push(entry?.path());
+        }
     }
 
-    fs::write(path.join(Path::new("inventory.compmeta")), metadata)?;
+    let mut referenced = HashSet::new();
 
-    Ok(paths_map)
+    for dir in dirs {
+        let inventory_file = dir.join(Path::new("inventory.compmeta"));
+
+        if inventory_file.exists() {
+            referenced.extend(read_inventory(&inventory_file)?.into_values().map(|entry| entry.hash));
+        }
+
+        for id in release_ids(&dir)? {
+            referenced.extend(parse_manifest(&read_release(&dir, &id)?)?.into_iter().filter(|f| f.is_file()).map(|f| f.get_hash().to_string()));
+        }
+    }
+
+    Ok(referenced)
 }
 
-struct ChachePart {
-    compmeta_line: String,
-    uncompressed_path: String,
-    compressed_path: String,
+/// Deletes the objects nothing refers to anymore, returns how many were deleted and their size.
+/// Also drops the `files` directory the cache used before objects were stored by hash.
+pub fn collect_garbage(cache: &Path) -> io::Result<(usize, u64)> {
+    let referenced = referenced_objects(cache)?;
+
+    let mut removed = 0;
+    let mut removed_bytes = 0;
+
+    let objects = cache.join("objects");
+
+    if objects.exists() {
+        for prefix in fs::read_dir(objects)? {
+            let prefix = prefix?;
+            let Some(prefix_name) = prefix.file_name().to_str().map(str::to_string) else { continue };
+
+            for object in fs::read_dir(prefix.path())? {
+                let object = object?;
+                let Some(object_name) = object.file_name().to_str().map(str::to_string) else { continue };
+
+                if referenced.contains(&format!("{prefix_name}{object_name}")) {
+                    continue;
+                }
+
+                removed_bytes += object.metadata()?.len();
+                fs::remove_file(object.path())?;
+                removed += 1;
+            }
+        }
+    }
+
+    let legacy_files = cache.join("files");
+    if legacy_files.is_dir() {
+        fs::remove_dir_all(legacy_files)?;
+    }
+
+    Ok((removed, removed_bytes))
 }
 
-impl ChachePart {
-    fn new(compmeta_line: String,
-    uncompressed_path: String,
-    compressed_path: String,) -> ChachePart {
-        ChachePart { compmeta_line, uncompressed_path, compressed_path }
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::hashed_files::par_hash;
+    use crate::testing::TempDir;
+
+    struct Fixture {
+        _dir: TempDir,
+        cache: PathBuf,
+        root: PathBuf,
+        files: Vec<HashedFile>,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let dir = TempDir::new();
+            let cache = dir.path().join("cache");
+            let root = dir.path().join("root");
+
+            fs::create_dir_all(&root).unwrap();
+            fs::write(root.join("a.txt"), b"first file".repeat(100)).unwrap();
+            fs::write(root.join("b.txt"), b"second file".repeat(100)).unwrap();
+
+            let files = par_hash(&root).unwrap();
+            create_cache(&cache, &cache, &root, &files, &|_| ()).unwrap();
+
+            Fixture { _dir: dir, cache, root, files }
+        }
+
+        fn object(&self, name: &str) -> PathBuf {
+            let file = self.files.iter().find(|f| f.get_path() == name).unwrap();
+            object_path(&self.cache, file.get_hash()).unwrap()
+        }
+
+        fn parse(&self, verify: bool) {
+            parse_cache(&self.cache, &self.cache, &self.root, &self.files, verify, &|_| ()).unwrap();
+        }
+    }
+
+    /// Overwrites the object in place with data of the same length, its stamp stays the same.
+    fn damage_in_place(object: &Path) -> SystemTime {
+        let modified = fs::metadata(object).unwrap().modified().unwrap();
+        let len = fs::metadata(object).unwrap().len() as usize;
+
+        let file = fs::OpenOptions::new().write(true).open(object).unwrap();
+        (&file).write_all(&vec![0xAA; len]).unwrap();
+        file.set_modified(modified).unwrap();
+
+        modified
+    }
+
+    fn is_intact(fixture: &Fixture, name: &str) -> bool {
+        fs::read(fixture.object(name)).unwrap() != vec![0xAA; fs::metadata(fixture.object(name)).unwrap().len() as usize]
+    }
+
+    #[test]
+    fn unchanged_object_is_trusted() {
+        let fixture = Fixture::new();
+        damage_in_place(&fixture.object("a.txt"));
+
+        fixture.parse(false);
+
+        // Not read again, so the damage went unnoticed.
+        assert!(!is_intact(&fixture, "a.txt"));
+    }
+
+    #[test]
+    fn verify_rehashes_unchanged_object() {
+        let fixture = Fixture::new();
+        damage_in_place(&fixture.object("a.txt"));
+
+        fixture.parse(true);
+
+        assert!(is_intact(&fixture, "a.txt"));
+    }
+
+    #[test]
+    fn touched_object_is_rehashed() {
+        let fixture = Fixture::new();
+        let modified = damage_in_place(&fixture.object("a.txt"));
+
+        fs::File::open(fixture.object("a.txt")).unwrap().set_modified(modified + Duration::from_secs(5)).unwrap();
+
+        fixture.parse(false);
+
+        assert!(is_intact(&fixture, "a.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaced_object_is_rehashed() {
+        let fixture = Fixture::new();
+        let object = fixture.object("a.txt");
+        let metadata = fs::metadata(&object).unwrap();
+
+        // Same size and modification time, only the inode tells it apart.
+        let replacement = object.with_extension("new");
+        fs::write(&replacement, vec![0xAA; metadata.len() as usize]).unwrap();
+        fs::File::open(&replacement).unwrap().set_modified(metadata.modified().unwrap()).unwrap();
+        fs::rename(&replacement, &object).unwrap();
+
+        fixture.parse(false);
+
+        assert!(is_intact(&fixture, "a.txt"));
+    }
+
+    #[test]
+    fn removed_object_is_the_only_one_recompressed() {
+        let fixture = Fixture::new();
+        let kept = ObjectStamp::read(&fixture.object("b.txt")).unwrap();
+
+        fs::remove_file(fixture.object("a.txt")).unwrap();
+
+        fixture.parse(false);
+
+        assert!(fixture.object("a.txt").exists());
+        assert_eq!(ObjectStamp::read(&fixture.object("b.txt")).unwrap(), kept);
+    }
+
+    #[test]
+    fn garbage_collection_keeps_released_objects() {
+        use crate::releases::{point_tag, store_release};
+
+        let fixture = Fixture::new();
+        let released_a = fixture.object("a.txt");
+
+        let id = store_release(&fixture.cache, &fixture.cache, &fixture.files).unwrap();
+        point_tag(&fixture.cache, "v1", &id).unwrap();
+
+        // a.txt changes, its old object is only referred to by the release now.
+        fs::write(fixture.root.join("a.txt"), b"changed").unwrap();
+        let files = par_hash(&fixture.root).unwrap();
+        update_cache(&fixture.cache, &fixture.cache, &fixture.root, &files, &|_| ()).unwrap();
+        let live_a = object_path(&fixture.cache, files.iter().find(|f| f.get_path() == "a.txt").unwrap().get_hash()).unwrap();
+
+        // A named repository whose only reference left is a release.
+        let other_root = fixture.root.with_file_name("other");
+        fs::create_dir_all(&other_root).unwrap();
+        fs::write(other_root.join("c.txt"), b"third file").unwrap();
+
+        let other_dir = repository_dir(&fixture.cache, "other");
+        let other_files = par_hash(&other_root).unwrap();
+        create_cache(&fixture.cache, &other_dir, &other_root, &other_files, &|_| ()).unwrap();
+        store_release(&fixture.cache, &other_dir, &other_files).unwrap();
+        fs::remove_file(other_dir.join("inventory.compmeta")).unwrap();
+        let released_c = object_path(&fixture.cache, other_files[0].get_hash()).unwrap();
+
+        let stray = object_path(&fixture.cache, &"f".repeat(64)).unwrap();
+        fs::create_dir_all(stray.parent().unwrap()).unwrap();
+        fs::write(&stray, b"stray").unwrap();
+
+        fs::create_dir_all(fixture.cache.join("files")).unwrap();
+        fs::write(fixture.cache.join("files/a.txt"), b"legacy").unwrap();
+
+        assert_eq!(collect_garbage(&fixture.cache).unwrap(), (1, 5));
+
+        assert!(!stray.exists());
+        assert!(!fixture.cache.join("files").exists());
+
+        for kept in [released_a, live_a, fixture.object("b.txt"), released_c] {
+            assert!(kept.exists(), "{kept:?} was collected");
+        }
     }
 }
@@ -1,5 +1,5 @@
 use std::{
-    fs, io, path::{Path, PathBuf}
+    collections::{HashMap, HashSet}, fs, io, path::{Component, Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}
 };
 
 use blake2::Blake2s256;
@@ -9,21 +9,155 @@ use file_hashing::get_hash_file;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use repairman_common::*;
 
+use crate::server::{Emit, ServerEvent};
 
+
+/// Size and modification time of a file, a rescan only hashes files whose stamp changed.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    fn from_metadata(metadata: &fs::Metadata) -> FileStamp {
+        FileStamp { len: metadata.len(), modified: metadata.modified().ok() }
+    }
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Directory the manifest paths of `root` are relative to, its parent if a single file is served.
+pub fn base_dir(root: &Path) -> &Path {
+    if root.is_dir() {
+        root
+    } else {
+        root.parent().unwrap_or(Path::new(""))
+    }
+}
+
+/// `file` relative to `base` with `/` between its parts, whatever the platform and however the root was given.
+/// `None` if the path isn't valid UTF-8, manifests can't carry it.
+fn manifest_path(base: &Path, file: &Path) -> io::Result<Option<String>> {
+    let relative = file.strip_prefix(base)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{file:?} isn't inside {base:?}")))?;
+
+    let mut parts = Vec::new();
+
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => match part.to_str() {
+                Some(part) => parts.push(part),
+                None => return Ok(None),
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{file:?} isn't inside {base:?}"))),
+        }
+    }
+
+    Ok(Some(parts.join("/")))
+}
+
+/// Files the manifest can't carry are left out without a word.
 pub fn par_hash(path: &Path) -> io::Result<Vec<HashedFile>> {
+    Ok(par_rehash(path, &HashMap::new(), &|_| ())?.into_iter().map(|(f, _)| f).collect())
+}
+
+/// Like `par_hash`, files whose stamp matches the one in `previous` keep the hash stored next to it.
+/// Directories and symlinks are listed with their metadata and an empty hash, symlinks aren't followed.
+/// Files the manifest can't carry are reported as `Skipped`.
+pub fn par_rehash(path: &Path, previous: &HashMap<String, (FileStamp, String)>, emit: Emit) -> io::Result<Vec<(HashedFile, FileStamp)>> {
     let files = get_files(path)?;
+    let base = base_dir(path);
+
+    let scanned: Vec<Option<(HashedFile, FileStamp)>> = files.par_iter().map(|f| {
+            let Some(path_str) = manifest_path(base, f)? else {
+                emit(ServerEvent::Skipped { path: f, reason: "its path isn't valid UTF-8" });
+                return Ok(None);
+            };
+
+            // A '\' in a name would split it in two on Windows clients.
+            if let Err(err) = check_manifest_path(&path_str) {
+                emit(ServerEvent::Skipped { path: f, reason: &err.to_string() });
+                return Ok(None);
+            }
+
+            // Taken before hashing, a change while hashing shows up as a new stamp next time.
+            let metadata = fs::symlink_metadata(f)?;
+            let stamp = FileStamp::from_metadata(&metadata);
+
+            let kind = if metadata.is_dir() {
+                FileKind::Directory
+            } else if metadata.is_symlink() {
+                let target = match fs::read_link(f)?.to_str() {
+                    Some(target) => target.to_string(),
+                    None => {
+                        emit(ServerEvent::Skipped { path: f, reason: "its link target isn't valid UTF-8" });
+                        return Ok(None);
+                    },
+                };
+
+                // Clients refuse the whole manifest over a link pointing outside of the root.
+                if let Err(err) = check_link_target(&path_str, &target) {
+                    emit(ServerEvent::Skipped { path: f, reason: &format!("its link target is refused: {err}") });
+                    return Ok(None);
+                }
+
+                FileKind::Symlink(target)
+            } else {
+                FileKind::Regular
+            };
+
+            let size = if kind == FileKind::Regular { metadata.len() } else { 0 };
+            let modified = metadata.modified().ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|since_epoch| since_epoch.as_secs());
+
+            let file_metadata = FileMetadata::new(kind, size, file_mode(&metadata), modified);
+
+            if file_metadata.get_kind() != &FileKind::Regular {
+                return Ok(Some((HashedFile::new(&path_str, "").with_metadata(file_metadata), stamp)));
+            }
+
+            if let Some((previous_stamp, hash)) = previous.get(&path_str) && *previous_stamp == stamp {
+                return Ok(Some((HashedFile::new(&path_str, hash).with_metadata(file_metadata), stamp)));
+            }
 
-    files.par_iter().map(|f| {
             let mut hasher = Blake2s256::new();
 
             let result_bytes = get_hash_file(f, &mut hasher)
                 .map_err(|e| io::Error::new(e.kind(), format!("Failed to hash {:?}: {}", f, e)))?;
 
-            let path_str = f.to_str()
-                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8 path"))?;
+            Ok(Some((HashedFile::new(&path_str, &result_bytes).with_metadata(file_metadata), stamp)))
+    }).collect::<io::Result<_>>()?;
+
+    let scanned: Vec<(HashedFile, FileStamp)> = scanned.into_iter().flatten().collect();
 
-            Ok(HashedFile::new(path_str, &result_bytes))
-    }).collect()
+    // Nothing may be listed below a symlink, writing it would go through the link.
+    let links: HashSet<&str> = scanned.iter()
+        .filter(|(f, _)| matches!(f.get_metadata().map(|m| m.get_kind()), Some(FileKind::Symlink(_))))
+        .map(|(f, _)| f.get_path())
+        .collect();
+
+    let below_link: HashSet<String> = scanned.iter()
+        .filter(|(f, _)| ancestors(f.get_path()).any(|parent| links.contains(parent)))
+        .map(|(f, _)| f.get_path().to_string())
+        .collect();
+
+    Ok(scanned.into_iter().filter(|(f, _)| !below_link.contains(f.get_path())).collect())
+}
+
+fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/').map(move |(i, _)| &path[..i])
 }
 
 fn get_files(origin_dir: &Path) -> io::Result<Vec<PathBuf>> {
@@ -46,13 +180,16 @@ fn get_files(origin_dir: &Path) -> io::Result<Vec<PathBuf>> {
 
 fn walkdir(dir: &Path, buffer: &mut Vec<PathBuf>) -> io::Result<()> {
     for entry in fs::read_dir(dir)? {
-        let entry = entry?.path();
+        let entry = entry?;
+        let is_dir = entry.file_type()?.is_dir();
+        let path = entry.path();
 
-        if entry.is_dir() {
-            walkdir(&entry, buffer)?;
-        } else {
-            buffer.push(entry);
+        // Symlinks to directories are listed as links, not walked.
+        if is_dir {
+            walkdir(&path, buffer)?;
         }
+
+        buffer.push(path);
     }
     Ok(())
 }
@@ -0,0 +1,343 @@
+use std::{
+    collections::HashMap, fs, io::{self, Write}, path::{Path, PathBuf}, time::UNIX_EPOCH,
+};
+
+// inventory.compmeta, all integers big endian:
+//   magic "RMIV", u16 format version, u64 entry count,
+//   per entry: u32 path length, path, u16 hash length, hash, u16 compressed hash length, compressed hash,
+//              u64 object size, u8 1 if a modification time follows, u64 seconds, u32 nanoseconds, u64 object inode,
+//   u32 CRC32 over everything before it.
+// Version 1 lacks the object stamp, caches from before the format was versioned hold "path\0hash\0compressed hash\0"
+// entries. Both are read as well and the next write turns them into the current format.
+
+const INVENTORY_MAGIC: [u8; 4] = *b"RMIV";
+const INVENTORY_VERSION: u16 = 2;
+
+/// Size, modification time and inode of a cached object. While they match the stored ones the object is
+/// taken to still have the compressed hash next to them, without reading it again.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ObjectStamp {
+    len: u64,
+    modified: Option<(u64, u32)>,
+    inode: u64,
+}
+
+impl ObjectStamp {
+    pub fn read(object: &Path) -> io::Result<ObjectStamp> {
+        let metadata = fs::metadata(object)?;
+
+        let modified = metadata.modified().ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| (since_epoch.as_secs(), since_epoch.subsec_nanos()));
+
+        Ok(ObjectStamp { len: metadata.len(), modified, inode: inode(&metadata) })
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+pub struct InventoryEntry {
+    pub hash: String,
+    pub compressed_hash: String,
+    /// `None` for entries of older inventories, their object has to be hashed once.
+    pub stamp: Option<ObjectStamp>,
+}
+
+/// Maps the path of every served file to its hash and the hash and stamp of its compressed object.
+pub type Inventory = HashMap<String, InventoryEntry>;
+
+fn damaged(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Cache inventory is damaged: {what}."))
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if data.len() < n {
+        return Err(damaged("it ends early"));
+    }
+
+    let (part, rest) = data.split_at(n);
+    *data = rest;
+    Ok(part)
+}
+
+fn take_u8(data: &mut &[u8]) -> io::Result<u8> {
+    Ok(take(data, 1)?[0])
+}
+
+fn take_u16(data: &mut &[u8]) -> io::Result<u16> {
+    Ok(u16::from_be_bytes(take(data, 2)?.try_into().map_err(|_| damaged("it ends early"))?))
+}
+
+fn take_u32(data: &mut &[u8]) -> io::Result<u32> {
+    Ok(u32::from_be_bytes(take(data, 4)?.try_into().map_err(|_| damaged("it ends early"))?))
+}
+
+fn take_u64(data: &mut &[u8]) -> io::Result<u64> {
+    Ok(u64::from_be_bytes(take(data, 8)?.try_into().map_err(|_| damaged("it ends early"))?))
+}
+
+fn take_str<'a>(data: &mut &'a [u8], len: usize) -> io::Result<&'a str> {
+    str::from_utf8(take(data, len)?).map_err(|_| damaged("an entry isn't valid UTF-8"))
+}
+
+fn decode_inventory(data: &[u8]) -> io::Result<Inventory> {
+    let Some((content, checksum)) = data.split_last_chunk::<4>() else {
+        return Err(damaged("it ends early"));
+    };
+
+    if crc32fast::hash(content) != u32::from_be_bytes(*checksum) {
+        return Err(damaged("the checksum doesn't match"));
+    }
+
+    let Some(mut data) = content.strip_prefix(&INVENTORY_MAGIC) else {
+        return Err(damaged("it ends early"));
+    };
+
+    let version = take_u16(&mut data)?;
+    if version == 0 || version > INVENTORY_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Cache inventory has the unknown format version {version}.")));
+    }
+
+    let count = take_u64(&mut data)?;
+    let mut inventory = Inventory::new();
+
+    for _ in 0..count {
+        let path_len = take_u32(&mut data)? as usize;
+        let path = take_str(&mut data, path_len)?;
+
+        let hash_len = take_u16(&mut data)? as usize;
+        let hash = take_str(&mut data, hash_len)?;
+
+        let compressed_hash_len = take_u16(&mut data)? as usize;
+        let compressed_hash = take_str(&mut data, compressed_hash_len)?;
+
+        let stamp = if version >= 2 {
+            let len = take_u64(&mut data)?;
+            let has_modified = take_u8(&mut data)?;
+            let modified = (take_u64(&mut data)?, take_u32(&mut data)?);
+            let inode = take_u64(&mut data)?;
+
+            Some(ObjectStamp { len, modified: (has_modified == 1).then_some(modified), inode })
+        } else {
+            None
+        };
+
+        inventory.insert(path.to_string(), InventoryEntry { hash: hash.to_string(), compressed_hash: compressed_hash.to_string(), stamp });
+    }
+
+    if !data.is_empty() {
+        return Err(damaged("there is data after the last entry"));
+    }
+
+    Ok(inventory)
+}
+
+fn decode_legacy_inventory(data: &[u8]) -> io::Result<Inventory> {
+    let mut segments = data.split(|b| *b == b'\0').map(|seg| {
+        str::from_utf8(seg).map_err(|_| damaged("an entry isn't valid UTF-8"))
+    });
+
+    let mut inventory = Inventory::new();
+
+    while let Some(path) = segments.next() {
+        let path = path?;
+
+        // The last entry ends on a NUL, which leaves an empty segment behind it.
+        if path.is_empty() {
+            continue;
+        }
+
+        let hash = segments.next().ok_or_else(|| damaged("an entry has no hash"))??;
+        let compressed_hash = segments.next().ok_or_else(|| damaged("an entry has no compressed hash"))??;
+
+        inventory.insert(path.to_string(), InventoryEntry { hash: hash.to_string(), compressed_hash: compressed_hash.to_string(), stamp: None });
+    }
+
+    Ok(inventory)
+}
+
+/// `InvalidData` if the file is damaged or from a newer version, the cache can be rebuilt from the objects then.
+pub fn read_inventory(inventory_file: &Path) -> io::Result<Inventory> {
+    let data = fs::read(inventory_file)?;
+
+    if data.starts_with(&INVENTORY_MAGIC) {
+        decode_inventory(&data)
+    } else {
+        decode_legacy_inventory(&data)
+    }
+}
+
+/// `entries` are served path, hash, compressed hash and object stamp. The file is written next to the old one
+/// and renamed over it, a crash leaves either the old or the new inventory behind.
+pub fn write_inventory<'a, I: ExactSizeIterator<Item = (&'a str, &'a str, &'a str, ObjectStamp)>>(inventory_file: &Path, entries: I) -> io::Result<()> {
+    let mut data = Vec::with_capacity(14 + 240 * entries.len());
+
+    data.extend_from_slice(&INVENTORY_MAGIC);
+    data.extend_from_slice(&INVENTORY_VERSION.to_be_bytes());
+    data.extend_from_slice(&(entries.len() as u64).to_be_bytes());
+
+    for (path, hash, compressed_hash, stamp) in entries {
+        let path_len = u32::try_from(path.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path is too long for the cache inventory."))?;
+        let hash_len = u16::try_from(hash.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Hash is too long for the cache inventory."))?;
+        let compressed_hash_len = u16::try_from(compressed_hash.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Hash is too long for the cache inventory."))?;
+
+        data.extend_from_slice(&path_len.to_be_bytes());
+        data.extend_from_slice(path.as_bytes());
+        data.extend_from_slice(&hash_len.to_be_bytes());
+        data.extend_from_slice(hash.as_bytes());
+        data.extend_from_slice(&compressed_hash_len.to_be_bytes());
+        data.extend_from_slice(compressed_hash.as_bytes());
+
+        let (modified_secs, modified_nanos) = stamp.modified.unwrap_or_default();
+
+        data.extend_from_slice(&stamp.len.to_be_bytes());
+        data.push(u8::from(stamp.modified.is_some()));
+        data.extend_from_slice(&modified_secs.to_be_bytes());
+        data.extend_from_slice(&modified_nanos.to_be_bytes());
+        data.extend_from_slice(&stamp.inode.to_be_bytes());
+    }
+
+    let checksum = crc32fast::hash(&data);
+    data.extend_from_slice(&checksum.to_be_bytes());
+
+    let mut os_tmp_path = inventory_file.to_path_buf().into_os_string();
+    os_tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(os_tmp_path);
+
+    let mut tmp_file = fs::File::create(&tmp_path)?;
+    tmp_file.write_all(&data)?;
+    tmp_file.sync_all()?;
+
+    fs::rename(tmp_path, inventory_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const STAMP: ObjectStamp = ObjectStamp { len: 1234, modified: Some((1_700_000_000, 42)), inode: 99 };
+
+    fn write_sample(file: &Path) {
+        let entries = [
+            ("a.txt", "aaaa", "1111", STAMP),
+            ("dir/name with spaces\n", "bbbb", "2222", ObjectStamp { len: 0, modified: None, inode: 0 }),
+        ];
+
+        write_inventory(file, entries.into_iter()).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new();
+        let file = dir.path().join("inventory.compmeta");
+        write_sample(&file);
+
+        let inventory = read_inventory(&file).unwrap();
+
+        assert_eq!(inventory.len(), 2);
+
+        let a = &inventory["a.txt"];
+        assert_eq!((a.hash.as_str(), a.compressed_hash.as_str(), a.stamp), ("aaaa", "1111", Some(STAMP)));
+
+        let b = &inventory["dir/name with spaces\n"];
+        assert_eq!(b.stamp, Some(ObjectStamp { len: 0, modified: None, inode: 0 }));
+        assert!(!dir.path().join("inventory.compmeta.tmp").exists());
+    }
+
+    #[test]
+    fn truncated_inventory_is_damaged() {
+        let dir = TempDir::new();
+        let file = dir.path().join("inventory.compmeta");
+        write_sample(&file);
+
+        let data = fs::read(&file).unwrap();
+
+        for len in [4, 6, 14, data.len() / 2, data.len() - 1] {
+            let err = decode_inventory(&data[..len]).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "length {len}");
+        }
+    }
+
+    #[test]
+    fn flipped_byte_fails_the_checksum() {
+        let dir = TempDir::new();
+        let file = dir.path().join("inventory.compmeta");
+        write_sample(&file);
+
+        let mut data = fs::read(&file).unwrap();
+        data[20] ^= 0x01;
+        fs::write(&file, &data).unwrap();
+
+        let err = read_inventory(&file).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("checksum"));
+    }
+
+    #[test]
+    fn unknown_version_is_refused() {
+        let mut data = INVENTORY_MAGIC.to_vec();
+        data.extend_from_slice(&(INVENTORY_VERSION + 1).to_be_bytes());
+        data.extend_from_slice(&0u64.to_be_bytes());
+        data.extend_from_slice(&crc32fast::hash(&data).to_be_bytes());
+
+        let err = decode_inventory(&data).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("unknown format version"));
+    }
+
+    #[test]
+    fn version_one_has_no_stamps() {
+        let mut data = INVENTORY_MAGIC.to_vec();
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&1u64.to_be_bytes());
+        data.extend_from_slice(&5u32.to_be_bytes());
+        data.extend_from_slice(b"a.txt");
+        data.extend_from_slice(&4u16.to_be_bytes());
+        data.extend_from_slice(b"aaaa");
+        data.extend_from_slice(&4u16.to_be_bytes());
+        data.extend_from_slice(b"1111");
+        data.extend_from_slice(&crc32fast::hash(&data).to_be_bytes());
+
+        let inventory = decode_inventory(&data).unwrap();
+        assert_eq!(inventory["a.txt"].compressed_hash, "1111");
+        assert_eq!(inventory["a.txt"].stamp, None);
+    }
+
+    #[test]
+    fn legacy_inventory_is_migrated() {
+        let dir = TempDir::new();
+        let file = dir.path().join("inventory.compmeta");
+        fs::write(&file, b"a.txt\0aaaa\x001111\0sub/b.txt\0bbbb\x002222\0").unwrap();
+
+        let inventory = read_inventory(&file).unwrap();
+        assert_eq!(inventory.len(), 2);
+        assert_eq!(inventory["sub/b.txt"].hash, "bbbb");
+        assert_eq!(inventory["sub/b.txt"].stamp, None);
+
+        // The next write brings it to the current format.
+        let entries: Vec<(&str, &str, &str, ObjectStamp)> = inventory.iter()
+            .map(|(path, entry)| (path.as_str(), entry.hash.as_str(), entry.compressed_hash.as_str(), STAMP))
+            .collect();
+        write_inventory(&file, entries.into_iter()).unwrap();
+
+        assert!(fs::read(&file).unwrap().starts_with(&INVENTORY_MAGIC));
+        assert_eq!(read_inventory(&file).unwrap()["a.txt"].stamp, Some(STAMP));
+    }
+
+    #[test]
+    fn legacy_entry_without_hash_is_damaged() {
+        let err = decode_legacy_inventory(b"a.txt\0aaaa").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,14 @@
+mod cache;
+mod hashed_files;
+mod inventory;
+mod releases;
+mod server;
+#[cfg(test)]
+mod testing;
+mod tls;
+
+pub use hashed_files::par_hash;
+pub use server::*;
+pub use tls::load_acceptor;
+
+pub use repairman_common::{ErrorResponse, HashedFile, LATEST_TAG, ManifestSigner, RequestVersion};
@@ -1,18 +1,24 @@
-use std::path::Path;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use server::run_server;
-
-use hashed_files::par_hash;
-use clap::{Parser};
-
-mod hashed_files;
-mod server;
-mod cache;
+use clap::Parser;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use repairman_server::{ManifestSigner, RepairServer, RepositoryConfig, ServerConfig, ServerEvent, load_acceptor};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    path: String,
+    /// Directory served to clients that don't name a repository
+    #[arg(required_unless_present = "repos")]
+    path: Option<String>,
+
+    /// Named repository, can be given several times
+    #[arg(long = "repo", value_name = "NAME=PATH")]
+    repos: Vec<String>,
 
     #[arg(short, long, default_value_t = 6767)]
     port: u16,
@@ -21,32 +27,244 @@ struct Args {
     address: String,
 
     #[arg(short, long)]
-    cache: Option<String>
+    cache: Option<String>,
+
+    /// Hash every cached file on startup instead of trusting the ones whose size, modification time and inode didn't change
+    #[arg(long, requires = "cache")]
+    verify_cache: bool,
+
+    /// PEM certificate chain, enables TLS together with --tls-key
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    /// PEM Ed25519 private key used to sign the manifest
+    #[arg(long)]
+    signing_key: Option<String>,
+
+    /// Seconds open transfers get to finish after SIGTERM or Ctrl-C
+    #[arg(long, default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// Seconds between rescans of the served path, changed files show up without a restart
+    #[arg(long)]
+    rescan: Option<u64>,
+
+    /// Store the hashed files of every repository as a release under this tag and exit, needs --cache
+    #[arg(long, requires = "cache")]
+    release: Option<String>,
+
+    /// Point a tag at another tag or release id and exit, for example to roll back, needs --cache
+    #[arg(long, value_name = "TAG=TARGET", requires = "cache")]
+    point_tag: Vec<String>,
+
+    /// Delete cached files that no repository or release refers to anymore, before serving
+    #[arg(long, requires = "cache")]
+    gc: bool,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    println!("path: {}, address: {}, port: {}", args.path, args.address, args.port);
+    println!("address: {}, port: {}", args.address, args.port);
+
+    let mut repositories = Vec::new();
+
+    if let Some(ref path) = args.path {
+        repositories.push(RepositoryConfig {
+            name: String::new(),
+            root: PathBuf::from(path),
+        });
+    }
+
+    for repo in &args.repos {
+        let Some((name, path)) = repo.split_once('=') else {
+            eprintln!("Repository {repo:?} isn't given as NAME=PATH");
+            return;
+        };
+
+        repositories.push(RepositoryConfig {
+            name: name.to_string(),
+            root: PathBuf::from(path),
+        });
+    }
+
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => match load_acceptor(Path::new(cert), Path::new(key)) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                eprintln!("Error loading TLS certificate: {}", err);
+                return;
+            },
+        },
+        _ => None,
+    };
 
-    let list = match par_hash(Path::new(&args.path)) {
-        Ok(w) => w,
+    let signer = match args.signing_key {
+        Some(ref key) => match ManifestSigner::from_pem_file(Path::new(key)) {
+            Ok(signer) => {
+                println!("Signing manifest with public key {}", signer.public_key_hex());
+                Some(signer)
+            },
+            Err(err) => {
+                eprintln!("Error loading signing key: {}", err);
+                return;
+            },
+        },
+        None => None,
+    };
+
+    let config = ServerConfig {
+        repositories,
+        cache: args.cache.as_ref().map(PathBuf::from),
+        verify_cache: args.verify_cache,
+        tls,
+        signer,
+        events: Some(Arc::new(print_event)),
+    };
+
+    let server = match RepairServer::new(config) {
+        Ok(s) => s,
         Err(err) => {
             eprintln!("Error getting file hashes: {}", err);
             return;
         },
     };
 
-    for item in &list {
-        println!("{}", item);
+    for name in server.get_repositories() {
+        if let Some(ref tag) = args.release {
+            match server.release(name, tag) {
+                Ok(id) => println!("Released {name:?} as {tag} ({id})"),
+                Err(err) => {
+                    eprintln!("Error storing release {tag} of {name:?}: {err}");
+                    return;
+                },
+            }
+        }
+
+        for point in &args.point_tag {
+            let Some((tag, target)) = point.split_once('=') else {
+                eprintln!("Tag {point:?} isn't given as TAG=TARGET");
+                return;
+            };
+
+            match server.point_tag(name, tag, target) {
+                Ok(id) => println!("Pointed {tag} of {name:?} at {id}"),
+                Err(err) => {
+                    eprintln!("Error pointing {tag} of {name:?} at {target}: {err}");
+                    return;
+                },
+            }
+        }
+    }
+
+    if args.gc {
+        match server.collect_garbage() {
+            Ok((count, bytes)) => println!("Deleted {count} unused cached files with {bytes} bytes"),
+            Err(err) => eprintln!("Error collecting garbage: {err}"),
+        }
+    }
+
+    for name in server.get_repositories() {
+        if !name.is_empty() {
+            println!("repository {name}:");
+        }
+
+        for item in server.get_files(name).unwrap_or_default() {
+            println!("{}", item);
+        }
+
+        match server.get_tags(name) {
+            Ok(tags) => for (tag, id) in tags {
+                println!("tag {tag}: {id}");
+            },
+            Err(err) => eprintln!("Error reading the tags of {name:?}: {err}"),
+        }
+    }
+
+    // Tags are read on every request, a running server on the same cache picks them up without this one serving too.
+    if args.release.is_some() || !args.point_tag.is_empty() {
+        return;
     }
 
-    match run_server(&list, &format!("{}:{}", args.address, args.port), args.cache).await {
-        Ok(_) => (),
+    if args.cache.is_some() {
+        println!("Caching done...\nListening now");
+    }
+
+    let server = server.shutdown_timeout(Duration::from_secs(args.shutdown_timeout));
+
+    let listener = match TcpListener::bind(format!("{}:{}", args.address, args.port)).await {
+        Ok(l) => l,
         Err(e) => {
             eprintln!("{e}");
             return;
         },
     };
+
+    let shutdown = CancellationToken::new();
+
+    let signal_token = shutdown.clone();
+    let shutdown_timeout = args.shutdown_timeout;
+    tokio::spawn(async move {
+        if let Err(err) = shutdown_signal().await {
+            eprintln!("Error listening for shutdown signals: {err}");
+            return;
+        }
+
+        println!("Shutting down, waiting up to {shutdown_timeout}s for open transfers");
+        signal_token.cancel();
+    });
+
+    if let Some(rescan) = args.rescan.filter(|secs| *secs > 0) {
+        let watcher = server.clone();
+        let watch_token = shutdown.clone();
+        tokio::spawn(async move { watcher.watch(Duration::from_secs(rescan), watch_token).await });
+    }
+
+    match server.serve(listener, shutdown).await {
+        Ok(summary) => {
+            println!("Served {} connections ({} failed), sent {} files with {} bytes",
+                summary.get_connections(), summary.get_failed(), summary.get_files_sent(), summary.get_bytes_sent());
+
+            if summary.get_cut_off() > 0 {
+                println!("Cut off {} connections that didn't finish in time", summary.get_cut_off());
+            }
+        },
+        Err(e) => eprintln!("{e}"),
+    }
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+fn print_event(event: &ServerEvent<'_>) {
+    match event {
+        ServerEvent::Failed { error, .. } => eprintln!("Error handeling a connection: {error}"),
+        ServerEvent::AcceptFailed { error } => eprintln!("Error accepting a connection: {error}"),
+        ServerEvent::Reloaded { repository, files, changed } => println!("Reloaded the manifest of {repository:?}, {changed} changes, now serving {files} files"),
+        ServerEvent::ReloadFailed { repository, error } => eprintln!("Error rescanning {repository:?}, still serving the old manifest: {error}"),
+        ServerEvent::Skipped { path, reason } => eprintln!("Skipping {path:?}, {reason}"),
+        ServerEvent::CacheInvalid { reason } => println!("Cache was invalid, redoing it: {reason}"),
+        ServerEvent::CacheProgress { done: 0, total } => println!("Caching {total} files..."),
+        ServerEvent::CacheProgress { done, total } => println!("Cached {done} of {total} files"),
+        _ => (),
+    }
 }
@@ -0,0 +1,277 @@
+use std::{
+    fs, io, path::{Path, PathBuf}
+};
+
+use blake2::Blake2s256;
+use digest::Digest;
+
+use repairman_common::*;
+
+use crate::cache::{check_hex, object_path};
+
+/// Stored releases start with this, followed by the manifest entries with metadata. The NUL keeps it apart from
+/// older releases, see `parse_manifest`.
+const RELEASE_MAGIC: [u8; 4] = *b"\0RML";
+
+/// Manifests are stored under the hash of their stored form, so a snapshot never changes once written.
+pub fn manifest_id(manifest: &[u8]) -> String {
+    Blake2s256::digest(manifest).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn check_tag_name(tag: &str) -> io::Result<()> {
+    if tag.is_empty() || tag.starts_with('.') || !tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid tag name {tag:?}.")));
+    }
+
+    Ok(())
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut os_tmp_path = path.to_path_buf().into_os_string();
+    os_tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(os_tmp_path);
+
+    fs::write(&tmp_path, contents)?;
+    fs::rename(tmp_path, path)
+}
+
+/// Stores `files` as a release of the repository in `dir`. Their content is served from the cache objects,
+/// which every release with the same content shares.
+pub fn store_release(cache: &Path, dir: &Path, files: &[HashedFile]) -> io::Result<String> {
+    let mut manifest = RELEASE_MAGIC.to_vec();
+
+    for file in files {
+        if file.is_file() && !object_path(cache, file.get_hash())?.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} isn't in the cache.", file.get_path())));
+        }
+
+        encode_manifest_entry(&mut manifest, file, true)?;
+    }
+
+    let id = manifest_id(&manifest);
+    let release = dir.join("releases").join(&id);
+
+    if !release.exists() {
+        write_atomic(&release, &manifest)?;
+    }
+
+    Ok(id)
+}
+
+/// Ids of all releases stored in `dir`.
+pub fn release_ids(dir: &Path) -> io::Result<Vec<String>> {
+    let releases = dir.join("releases");
+
+    if !releases.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut ids = Vec::new();
+
+    for entry in fs::read_dir(releases)? {
+        if let Some(id) = entry?.file_name().to_str() && check_hex(id, "Release").is_ok() {
+            ids.push(id.to_string());
+        }
+    }
+
+    Ok(ids)
+}
+
+pub fn read_release(dir: &Path, id: &str) -> io::Result<Vec<u8>> {
+    check_hex(id, "Release")?;
+
+    fs::read(dir.join("releases").join(id))
+}
+
+/// `NotFound` if the tag doesn't exist.
+pub fn read_tag(dir: &Path, tag: &str) -> io::Result<String> {
+    check_tag_name(tag)?;
+
+    let id = fs::read_to_string(dir.join("tags").join(tag))?;
+    let id = id.trim();
+
+    check_hex(id, "Release")?;
+
+    Ok(id.to_string())
+}
+
+/// Points `tag` at the release `target`, which is another tag or a release id.
+pub fn point_tag(dir: &Path, tag: &str, target: &str) -> io::Result<String> {
+    check_tag_name(tag)?;
+
+    if tag == LATEST_TAG {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The latest tag always names the live files."));
+    }
+
+    let id = match read_tag(dir, target) {
+        Ok(id) => id,
+        Err(err) if err.kind() == io::ErrorKind::NotFound || err.kind() == io::ErrorKind::InvalidInput => target.to_string(),
+        Err(err) => return Err(err),
+    };
+
+    check_hex(&id, "Release")?;
+
+    if !dir.join("releases").join(&id).exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No tag or release named {target:?}.")));
+    }
+
+    write_atomic(&dir.join("tags").join(tag), format!("{id}\n").as_bytes())?;
+
+    Ok(id)
+}
+
+/// All tags with the release they point to, sorted by name.
+pub fn list_tags(dir: &Path) -> io::Result<Vec<(String, String)>> {
+    let tags_dir = dir.join("tags");
+
+    if !tags_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut tags = Vec::new();
+
+    for entry in fs::read_dir(tags_dir)? {
+        let entry = entry?;
+
+        let Some(tag) = entry.file_name().to_str().map(str::to_string) else { continue };
+        if check_tag_name(&tag).is_err() || tag.ends_with(".tmp") {
+            continue;
+        }
+
+        let id = read_tag(dir, &tag)?;
+        tags.push((tag, id));
+    }
+
+    tags.sort_unstable();
+
+    Ok(tags)
+}
+
+/// Reads the file list back out of a stored release. Older releases are a GIVE-HASHES body, either length-prefixed
+/// without metadata or "path hash" lines. A length-prefixed one starts with the high byte of a path length, which is
+/// never a character of such a line.
+pub fn parse_manifest(manifest: &[u8]) -> io::Result<Vec<HashedFile>> {
+    if let Some(entries) = manifest.strip_prefix(&RELEASE_MAGIC) {
+        let mut files = Vec::new();
+        decode_manifest(entries, true, &mut files)?;
+        return Ok(files);
+    }
+
+    if manifest.first() == Some(&0) {
+        let mut files = Vec::new();
+        decode_manifest(manifest, false, &mut files)?;
+        return Ok(files);
+    }
+
+    let manifest = str::from_utf8(manifest)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Stored manifest isn't valid UTF-8."))?;
+
+    manifest.lines().map(|line| {
+        let (path, hash) = line.rsplit_once(' ')
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Stored manifest contains an invalid line."))?;
+
+        Ok(HashedFile::new(path, hash))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TempDir;
+
+    const ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn release(dir: &Path, id: &str) {
+        write_atomic(&dir.join("releases").join(id), b"a.txt aaaa\n").unwrap();
+    }
+
+    #[test]
+    fn parse_text_release() {
+        let files = parse_manifest(b"a.txt aaaa\nwith space.txt bbbb\n").unwrap();
+
+        assert_eq!(files, [HashedFile::new("a.txt", "aaaa"), HashedFile::new("with space.txt", "bbbb")]);
+        assert!(parse_manifest(b"no-hash\n").is_err());
+        assert!(parse_manifest(b"\xff aaaa\n").is_err());
+    }
+
+    #[test]
+    fn parse_length_prefixed_release() {
+        let files = [HashedFile::new("a\nb.txt", "aaaa"), HashedFile::new("c.txt", "bbbb")];
+
+        let mut manifest = Vec::new();
+        for file in &files {
+            encode_manifest_entry(&mut manifest, file, false).unwrap();
+        }
+
+        assert_eq!(parse_manifest(&manifest).unwrap(), files);
+    }
+
+    #[test]
+    fn parse_release_with_metadata() {
+        let files = [
+            HashedFile::new("d", "").with_metadata(FileMetadata::new(FileKind::Directory, 0, Some(0o755), Some(1))),
+            HashedFile::new("d/f", "aaaa").with_metadata(FileMetadata::new(FileKind::Regular, 4, Some(0o644), Some(2))),
+        ];
+
+        let mut manifest = RELEASE_MAGIC.to_vec();
+        for file in &files {
+            encode_manifest_entry(&mut manifest, file, true).unwrap();
+        }
+
+        assert_eq!(parse_manifest(&manifest).unwrap(), files);
+        assert!(parse_manifest(&manifest[..manifest.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn point_tag_at_release_and_tag() {
+        let dir = TempDir::new();
+        release(dir.path(), ID);
+
+        assert_eq!(point_tag(dir.path(), "v1", ID).unwrap(), ID);
+        assert_eq!(point_tag(dir.path(), "stable", "v1").unwrap(), ID);
+
+        assert_eq!(read_tag(dir.path(), "stable").unwrap(), ID);
+        assert_eq!(list_tags(dir.path()).unwrap(), [("stable".to_string(), ID.to_string()), ("v1".to_string(), ID.to_string())]);
+    }
+
+    #[test]
+    fn point_tag_moves_the_tag() {
+        let dir = TempDir::new();
+        let older = "f".repeat(64);
+        release(dir.path(), ID);
+        release(dir.path(), &older);
+
+        point_tag(dir.path(), "stable", ID).unwrap();
+        point_tag(dir.path(), "stable", &older).unwrap();
+
+        assert_eq!(read_tag(dir.path(), "stable").unwrap(), older);
+    }
+
+    #[test]
+    fn point_tag_refuses_bad_targets() {
+        let dir = TempDir::new();
+        release(dir.path(), ID);
+
+        assert_eq!(point_tag(dir.path(), LATEST_TAG, ID).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(point_tag(dir.path(), "../x", ID).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(point_tag(dir.path(), "v1", &"e".repeat(64)).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(point_tag(dir.path(), "v1", "missing-tag").unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        assert!(list_tags(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_tag_checks_its_content() {
+        let dir = TempDir::new();
+
+        assert_eq!(read_tag(dir.path(), "v1").unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        write_atomic(&dir.path().join("tags").join("v1"), b"../../etc\n").unwrap();
+        assert_eq!(read_tag(dir.path(), "v1").unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,156 +1,1383 @@
 use std::{
-    collections::HashMap, io::{self, Write}, path::Path, sync::Arc
+    collections::{BTreeMap, HashMap, HashSet}, io::{self, Read, SeekFrom, Write}, net::SocketAddr, path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, RwLock, atomic::{AtomicU64, Ordering}}, time::Duration,
 };
 
 
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, DuplexStream},
+    net::TcpListener,
+    fs, sync::mpsc, task::{self, JoinSet}, time,
 };
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{io::SyncIoBridge, sync::CancellationToken};
 
-use flate2::{Compression, write::DeflateEncoder};
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
 
 use crate::cache::*;
+use crate::hashed_files::{FileStamp, base_dir, par_rehash};
+use crate::releases::*;
 use repairman_common::*;
 
-pub async fn run_server(files: &[HashedFile], addr: &str, cache: Option<String>) -> std::io::Result<()> {
-    let listener = TcpListener::bind(addr).await?;
+/// A directory served under `name`. The unnamed repository answers clients that don't ask for one,
+/// as does the only repository of a server that has just one.
+pub struct RepositoryConfig {
+    pub name: String,
+    pub root: PathBuf,
+}
+
+pub struct ServerConfig {
+    pub repositories: Vec<RepositoryConfig>,
+    /// Compressed files of all repositories, stored by content so identical files are only kept once.
+    pub cache: Option<PathBuf>,
+    /// Hash every cached object on startup instead of trusting the ones whose size, modification time and inode didn't change.
+    pub verify_cache: bool,
+    pub tls: Option<TlsAcceptor>,
+    pub signer: Option<ManifestSigner>,
+    /// Gets the events of the startup scan and caching already, `on_event` can replace it afterwards.
+    pub events: Option<EventCallback>,
+}
+
+/// Events of the connections, `id` tells them apart, of the scans and caching and of the rescans started by `watch`.
+pub enum ServerEvent<'a> {
+    Connected { id: u64, peer: Option<SocketAddr> },
+    Negotiated { id: u64, version: RequestVersion },
+    /// A file went out completely, `bytes` as sent over the wire.
+    FileSent { id: u64, path: &'a str, bytes: u64 },
+    ErrorSent { id: u64, error: &'a ErrorResponse },
+    Closed { id: u64 },
+    Failed { id: u64, error: &'a io::Error },
+    /// Accepting a connection failed, `serve` waits a moment and keeps listening.
+    AcceptFailed { error: &'a io::Error },
+    /// A rescan found changes and swapped in a new manifest, `changed` counts new, changed and removed files.
+    Reloaded { repository: &'a str, files: usize, changed: usize },
+    ReloadFailed { repository: &'a str, error: &'a io::Error },
+    /// A file the manifest can't carry was left out of it.
+    Skipped { path: &'a Path, reason: &'a str },
+    /// The cache didn't match the served files, the objects and inventory are redone.
+    CacheInvalid { reason: &'a str },
+    /// `done` of `total` files are compressed into the cache, sent when it starts and about every tenth.
+    CacheProgress { done: usize, total: usize },
+}
+
+pub type EventCallback = Arc<dyn Fn(&ServerEvent<'_>) + Send + Sync>;
+
+/// Where the scans and the cache report to, the server's callback if it has one.
+pub(crate) type Emit<'e> = &'e (dyn Fn(ServerEvent<'_>) + Sync);
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const MANIFEST_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Default)]
+struct ServerStats {
+    connections: AtomicU64,
+    failed: AtomicU64,
+    files_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+/// What a `RepairServer` did until `serve` returned, counted over all of its clones.
+pub struct ServeSummary {
+    connections: u64,
+    failed: u64,
+    files_sent: u64,
+    bytes_sent: u64,
+    cut_off: usize,
+}
+
+impl ServeSummary {
+    pub fn get_connections(&self) -> u64 {
+        self.connections
+    }
+
+    pub fn get_failed(&self) -> u64 {
+        self.failed
+    }
+
+    pub fn get_files_sent(&self) -> u64 {
+        self.files_sent
+    }
+
+    pub fn get_bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Connections that were still busy when the shutdown timeout ran out.
+    pub fn get_cut_off(&self) -> usize {
+        self.cut_off
+    }
+}
+
+/// A GIVE-HASHES body with its digest and entry count.
+struct EncodedManifest {
+    manifest: Vec<u8>,
+    digest: [u8; 32],
+    count: usize,
+}
+
+fn encode_manifest(files: &[HashedFile], format: ManifestFormat) -> io::Result<EncodedManifest> {
+    let mut manifest = Vec::new();
+    let mut count = 0;
 
-    // Create the GIVE-HASHES response to reuse, body contains "file_name hash" on sperated lines
-    let mut body = String::new();
     for file in files {
-        body.push_str(format!("{} {}\n", file.get_path(), file.get_hash()).as_str());
+        match format {
+            ManifestFormat::Metadata => encode_manifest_entry(&mut manifest, file, true)?,
+            // Older clients would take directories and symlinks for files.
+            _ if !file.is_file() => continue,
+            ManifestFormat::LengthPrefixed => encode_manifest_entry(&mut manifest, file, false)?,
+            // Files whose name has a space or newline can't be written as a line, those clients don't get them.
+            ManifestFormat::Text if file.get_path().contains([' ', '\n', '\r']) => continue,
+            ManifestFormat::Text => manifest.extend_from_slice(format!("{} {}\n", file.get_path(), file.get_hash()).as_bytes()),
+        }
+
+        count += 1;
     }
 
-    let body_size = body.len() as u32;
-    let header = create_header(RequestVersion::ZEROpOne, RequestType::GiveHashes, 0, body_size);
+    let digest = manifest_digest(&manifest);
 
-    let mut hashes = Vec::with_capacity(body.len() + header.len());
-    hashes.extend_from_slice(&header);
-    hashes.extend_from_slice(body.as_bytes());
+    Ok(EncodedManifest { manifest, digest, count })
+}
 
-    let hashes = Arc::new(hashes);
-    let mut paths_map = None;
+/// Manifest path the symlink at `link` points to. The target already passed `check_link_target`, so `..` only
+/// shows up at its start.
+fn link_destination(link: &str, target: &str) -> Option<String> {
+    let mut parts: Vec<&str> = link.split('/').collect();
+    parts.pop();
 
-    if let Some(ref path) = cache {
-        let path = Path::new(&path);
-        if path.exists() {
-            paths_map = Some(parse_cache(path, files)?);
-        } else {
-            paths_map = Some(create_cache(path, files)?);
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::ParentDir => { parts.pop()?; },
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+
+    Some(parts.join("/"))
+}
+
+/// What the symlinks inside the root are for clients without the metadata capability, which used to get them
+/// followed: a link to a regular file is a copy of it, a link to a directory a copy of the files in it. Maps every
+/// copy to the regular file it's read from. Links found inside a linked directory are only copied if they point
+/// to a file.
+fn linked_files(files: &[HashedFile]) -> BTreeMap<String, String> {
+    let links: Vec<(&str, String)> = files.iter()
+        .filter_map(|f| match f.get_metadata().map(|m| m.get_kind()) {
+            Some(FileKind::Symlink(target)) => Some((f.get_path(), link_destination(f.get_path(), target)?)),
+            _ => None,
+        })
+        .collect();
+
+    let regular: HashSet<&str> = files.iter().filter(|f| f.is_file()).map(|f| f.get_path()).collect();
+    let directories: HashSet<&str> = files.iter()
+        .filter(|f| f.get_metadata().is_some_and(|m| m.get_kind() == &FileKind::Directory))
+        .map(|f| f.get_path())
+        .collect();
+
+    let mut linked: BTreeMap<String, String> = links.iter()
+        .filter(|(_, destination)| regular.contains(destination.as_str()))
+        .map(|(link, destination)| (link.to_string(), destination.clone()))
+        .collect();
+
+    // Everything a linked directory can hand out, by the path it has below the root.
+    let mut sources: BTreeMap<&str, &str> = regular.iter().map(|path| (*path, *path)).collect();
+    sources.extend(linked.iter().map(|(link, destination)| (link.as_str(), destination.as_str())));
+
+    let mut copies = Vec::new();
+
+    for (link, destination) in &links {
+        if !directories.contains(destination.as_str()) {
+            continue;
         }
 
-        println!("Caching done...\nListening now");
+        let prefix = format!("{destination}/");
+
+        for (path, source) in sources.range(prefix.as_str()..).take_while(|(path, _)| path.starts_with(&prefix)) {
+            copies.push((format!("{link}/{}", &path[prefix.len()..]), source.to_string()));
+        }
     }
 
-    let paths_map = Arc::new(paths_map);
+    linked.extend(copies);
+    linked
+}
 
-    loop {
-        let (stream, _) = listener.accept().await?;
+struct ServerState {
+    files: Vec<HashedFile>,
+    /// Copies of the files symlinks inside the root lead to, by their path for clients without the metadata
+    /// capability, with the path of the regular file they're read from.
+    linked_files: BTreeMap<String, String>,
+    manifest: EncodedManifest,
+    /// Built the first time an older client asks.
+    length_prefixed: OnceLock<io::Result<EncodedManifest>>,
+    text: OnceLock<io::Result<EncodedManifest>>,
+    paths_map: Option<HashMap<String, String>>,
+    advertised: HashSet<String>,
+    /// Directory the manifest paths are relative to.
+    root: PathBuf,
+    /// Release snapshots are served from their objects only, the live files may have moved on since.
+    frozen: bool,
+}
 
-        let hashes_clone = Arc::clone(&hashes);
-        let clone_paths_map = Arc::clone(&paths_map);
+impl ServerState {
+    fn new(files: Vec<HashedFile>, mut paths_map: Option<HashMap<String, String>>, root: &Path) -> io::Result<ServerState> {
+        let manifest = encode_manifest(&files, ManifestFormat::Metadata)?;
 
-        
-        tokio::spawn(async move {
-            handle_connection(stream, hashes_clone, clone_paths_map).await.unwrap_or_else(|err| {
-                eprintln!("Error handeling a connection: {err}");
-            });
-        });
+        let linked_files = linked_files(&files);
+
+        // Without a cache the links are followed when the file is opened.
+        if let Some(ref mut paths_map) = paths_map {
+            for (link, target) in &linked_files {
+                if let Some(object) = paths_map.get(target).cloned() {
+                    paths_map.insert(link.clone(), object);
+                }
+            }
+        }
+
+        let advertised = files.iter()
+            .filter(|f| f.is_file())
+            .map(|f| f.get_path().to_string())
+            .chain(linked_files.keys().cloned())
+            .collect();
+
+        Ok(ServerState {
+            files,
+            linked_files,
+            manifest,
+            length_prefixed: OnceLock::new(),
+            text: OnceLock::new(),
+            paths_map,
+            advertised,
+            root: base_dir(root).to_path_buf(),
+            frozen: false,
+        })
+    }
+
+    fn manifest(&self, format: ManifestFormat) -> io::Result<&EncodedManifest> {
+        let older = match format {
+            ManifestFormat::Metadata => return Ok(&self.manifest),
+            ManifestFormat::LengthPrefixed => &self.length_prefixed,
+            ManifestFormat::Text => &self.text,
+        };
+
+        older.get_or_init(|| encode_manifest(&self.compat_files(), format)).as_ref()
+            .map_err(|err| io::Error::new(err.kind(), err.to_string()))
+    }
+
+    /// The files as clients without the metadata capability see them, the copies of linked files in place of the link.
+    fn compat_files(&self) -> Vec<HashedFile> {
+        let hashes: HashMap<&str, &str> = self.files.iter().map(|f| (f.get_path(), f.get_hash())).collect();
+
+        let mut compat = Vec::with_capacity(self.files.len());
+
+        for file in &self.files {
+            if !matches!(file.get_metadata().map(|m| m.get_kind()), Some(FileKind::Symlink(_))) {
+                compat.push(file.clone());
+                continue;
+            }
+
+            let prefix = format!("{}/", file.get_path());
+
+            let copies = self.linked_files.get_key_value(file.get_path()).into_iter()
+                .chain(self.linked_files.range(prefix.clone()..).take_while(|(path, _)| path.starts_with(&prefix)));
+
+            for (path, source) in copies {
+                if let Some(hash) = hashes.get(source.as_str()) {
+                    compat.push(HashedFile::new(path, hash));
+                }
+            }
+        }
+
+        compat
+    }
+
+    fn cached_path(&self, file_name: &str) -> io::Result<PathBuf> {
+        match self.paths_map.as_ref().and_then(|paths_map| paths_map.get(file_name)) {
+            Some(p) => Ok(PathBuf::from(p)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid file requested by client.")),
+        }
+    }
+}
+
+type Stamps = HashMap<String, (FileStamp, String)>;
+
+/// One served directory, with everything a rescan needs to build its next `ServerState`.
+struct Repository {
+    state: RwLock<Arc<ServerState>>,
+    root: PathBuf,
+    stamps: Mutex<Stamps>,
+    /// Releases loaded so far by id, they never change once stored.
+    releases: Mutex<HashMap<String, Arc<ServerState>>>,
+}
+
+impl Repository {
+    fn snapshot(&self) -> Arc<ServerState> {
+        // Writers only swap the Arc, a poisoned lock still holds a complete state.
+        match self.state.read() {
+            Ok(state) => Arc::clone(&state),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner()),
+        }
+    }
+}
+
+fn stamps_of(scanned: &[(HashedFile, FileStamp)]) -> Stamps {
+    scanned.iter().map(|(f, stamp)| (f.get_path().to_string(), (*stamp, f.get_hash().to_string()))).collect()
+}
+
+/// Names end up in request headers and cache paths, so they are kept to a safe set of characters.
+fn check_repository_name(name: &str) -> io::Result<()> {
+    if name.starts_with('.') || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid repository name {name:?}.")));
+    }
+
+    Ok(())
+}
+
+fn find_repository<'a, T>(repositories: &'a HashMap<String, T>, name: &str) -> Option<(&'a String, &'a T)> {
+    match repositories.get_key_value(name) {
+        Some(repository) => Some(repository),
+        None if name.is_empty() && repositories.len() == 1 => repositories.iter().next(),
+        None => None,
     }
+}
+
+fn unknown_repository(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("No repository named {name:?}."))
+}
+
+/// Repository key, the tag as the client asked for it and the state it resolved to.
+type Selected<'a> = Option<(&'a String, String, Arc<ServerState>)>;
+
+/// The release the last GET-HASHES picked if it was for the same repository, the live files otherwise. Comes with
+/// the tag it was asked for by.
+fn requested_state<'a>(snapshots: &'a HashMap<String, Arc<ServerState>>, selected: &'a Selected<'a>, name: &str) -> Option<(&'a ServerState, &'a str)> {
+    let (key, live) = find_repository(snapshots, name)?;
+
+    match selected {
+        Some((selected_key, tag, state)) if *selected_key == key => Some((state, tag)),
+        _ => Some((live, LATEST_TAG)),
+    }
+}
 
-    // Ok(())
+/// Serves one or more directories, cloning it is cheap and every clone serves the same files.
+#[derive(Clone)]
+pub struct RepairServer {
+    repositories: Arc<HashMap<String, Repository>>,
+    cache: Option<Arc<Path>>,
+    signer: Option<Arc<ManifestSigner>>,
+    hello: Arc<Hello>,
+    tls: Option<TlsAcceptor>,
+    events: Option<EventCallback>,
+    shutdown_timeout: Duration,
+    stats: Arc<ServerStats>,
 }
 
-async fn handle_connection(mut stream: TcpStream, hashes: Arc<Vec<u8>>, paths_map: Arc<Option<HashMap<String, String>>>) -> std::io::Result<()> {
-    loop {
-        let request = async_parse_request(&mut stream).await?;
+impl RepairServer {
+    /// Hashes the served directories and creates or checks their caches, this blocks until all are done.
+    pub fn new(config: ServerConfig) -> io::Result<RepairServer> {
+        if config.repositories.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No repository to serve."));
+        }
+
+        let mut repositories = HashMap::with_capacity(config.repositories.len());
+
+        let events = config.events;
+        let emit = |event: ServerEvent<'_>| if let Some(ref callback) = events {
+            callback(&event);
+        };
+
+        for repository in config.repositories {
+            check_repository_name(&repository.name)?;
+
+            if repositories.contains_key(&repository.name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Repository {:?} is configured twice.", repository.name)));
+            }
+
+            let scanned = par_rehash(&repository.root, &HashMap::new(), &emit)?;
+            let stamps = stamps_of(&scanned);
+            let files: Vec<HashedFile> = scanned.into_iter().map(|(f, _)| f).collect();
+
+            let mut paths_map = None;
+
+            if let Some(ref path) = config.cache {
+                let dir = repository_dir(path, &repository.name);
+
+                let root = base_dir(&repository.root);
+
+                if path.exists() {
+                    paths_map = Some(parse_cache(path, &dir, root, &files, config.verify_cache, &emit)?);
+                } else {
+                    paths_map = Some(create_cache(path, &dir, root, &files, &emit)?);
+                }
+            }
+
+            let state = ServerState::new(files, paths_map, &repository.root)?;
+
+            repositories.insert(repository.name, Repository {
+                state: RwLock::new(Arc::new(state)),
+                root: repository.root,
+                stamps: Mutex::new(stamps),
+                releases: Mutex::new(HashMap::new()),
+            });
+        }
+
+        let mut capabilities = vec![Capability::Deflate, Capability::Delta, Capability::Resume, Capability::Checksum, Capability::Repositories, Capability::Releases, Capability::ManifestStream, Capability::LengthPrefixed, Capability::Metadata];
+        if config.signer.is_some() {
+            capabilities.push(Capability::Signature);
+        }
+
+        Ok(RepairServer {
+            repositories: Arc::new(repositories),
+            cache: config.cache.map(Arc::from),
+            signer: config.signer.map(Arc::new),
+            hello: Arc::new(Hello::new(&SUPPORTED_VERSIONS, &capabilities)),
+            tls: config.tls,
+            events,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            stats: Arc::new(ServerStats::default()),
+        })
+    }
+
+    pub fn on_event<F: Fn(&ServerEvent<'_>) + Send + Sync + 'static>(mut self, callback: F) -> RepairServer {
+        self.events = Some(Arc::new(callback));
+        self
+    }
+
+    /// How long `serve` waits for open connections after the shutdown, before it cuts them off.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> RepairServer {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Names of the served repositories, sorted.
+    pub fn get_repositories(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.repositories.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// The files of the manifest `repository` serves right now, `None` if there is no such repository.
+    pub fn get_files(&self, repository: &str) -> Option<Vec<HashedFile>> {
+        find_repository(&self.repositories, repository).map(|(_, r)| r.snapshot().files.clone())
+    }
+
+    /// Stores the manifest `repository` serves right now as a release and points `tag` at it, returns the release id.
+    /// The release is served from the compressed files, so the repository needs a cache.
+    pub fn release(&self, repository: &str, tag: &str) -> io::Result<String> {
+        check_tag_name(tag)?;
+
+        let (name, repo) = find_repository(&self.repositories, repository).ok_or_else(|| unknown_repository(repository))?;
+        let cache = self.get_cache()?;
+        let dir = repository_dir(cache, name);
+
+        let state = repo.snapshot();
+
+        let id = store_release(cache, &dir, &state.files)?;
+        point_tag(&dir, tag, &id)
+    }
+
+    /// Points `tag` at the release `target` names, a tag or a release id. Rolling back is pointing a tag at an older release.
+    pub fn point_tag(&self, repository: &str, tag: &str, target: &str) -> io::Result<String> {
+        let (name, _) = find_repository(&self.repositories, repository).ok_or_else(|| unknown_repository(repository))?;
+
+        point_tag(&repository_dir(self.get_cache()?, name), tag, target)
+    }
+
+    /// The tags of `repository` with the release id each points to.
+    pub fn get_tags(&self, repository: &str) -> io::Result<Vec<(String, String)>> {
+        let (name, _) = find_repository(&self.repositories, repository).ok_or_else(|| unknown_repository(repository))?;
+
+        match self.cache {
+            Some(ref cache) => list_tags(&repository_dir(cache, name)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Deletes the cached objects no repository and no stored release refers to anymore, returns their count and size.
+    /// Connections still on a manifest from before a rescan can lose files they were about to get.
+    pub fn collect_garbage(&self) -> io::Result<(usize, u64)> {
+        let cache = self.get_cache()?;
+
+        // No rescan may write an inventory while the references are gathered.
+        let _stamps = self.repositories.values()
+            .map(|repository| repository.stamps.lock().map_err(|_| io::Error::other("A previous rescan panicked.")))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        collect_garbage(cache)
+    }
+
+    fn get_cache(&self) -> io::Result<&Path> {
+        self.cache.as_deref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Releases need a cache directory."))
+    }
+
+    /// `NotFound` if the repository has no such tag.
+    fn load_release(&self, repository: &str, tag: &str) -> io::Result<Arc<ServerState>> {
+        let repo = self.repositories.get(repository).ok_or_else(|| unknown_repository(repository))?;
+
+        let cache = self.cache.as_deref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Repository has no releases."))?;
+        let dir = repository_dir(cache, repository);
+
+        let id = read_tag(&dir, tag)?;
+
+        let mut releases = repo.releases.lock()
+            .map_err(|_| io::Error::other("A previous release load panicked."))?;
+
+        if let Some(state) = releases.get(&id) {
+            return Ok(Arc::clone(state));
+        }
+
+        let files = parse_manifest(&read_release(&dir, &id)?)?;
+
+        let paths_map = files.iter().filter(|f| f.is_file()).map(|f| {
+            Ok((f.get_path().to_string(), object_path_str(cache, f.get_hash())?))
+        }).collect::<io::Result<HashMap<String, String>>>()?;
+
+        let mut state = ServerState::new(files, Some(paths_map), &repo.root)?;
+        state.frozen = true;
+
+        let state = Arc::new(state);
+        releases.insert(id, Arc::clone(&state));
+
+        Ok(state)
+    }
+
+    /// Rescans every repository each `interval` until `shutdown` is cancelled. Only new and changed files
+    /// are hashed and cached again, connections keep the manifests they started with.
+    pub async fn watch(&self, interval: Duration, shutdown: CancellationToken) {
+        loop {
+            tokio::select! {
+                _ = time::sleep(interval) => (),
+                _ = shutdown.cancelled() => break,
+            }
+
+            for name in self.repositories.keys() {
+                let server = self.clone();
+                let repository = name.clone();
+
+                match task::spawn_blocking(move || server.reload(&repository)).await {
+                    Ok(Ok(Some((files, changed)))) => self.emit(ServerEvent::Reloaded { repository: name, files, changed }),
+                    Ok(Ok(None)) => (),
+                    Ok(Err(ref error)) => self.emit(ServerEvent::ReloadFailed { repository: name, error }),
+                    Err(err) => self.emit(ServerEvent::ReloadFailed { repository: name, error: &io::Error::other(err) }),
+                }
+            }
+        }
+    }
+
+    /// Rescans `repository` once and swaps in its new manifest if anything changed,
+    /// returns the file count and the number of changes.
+    pub fn reload(&self, repository: &str) -> io::Result<Option<(usize, usize)>> {
+        let (name, repository) = find_repository(&self.repositories, repository).ok_or_else(|| unknown_repository(repository))?;
+
+        // Held for the whole rescan, so two of them never race on the cache.
+        let mut stamps = repository.stamps.lock()
+            .map_err(|_| io::Error::other("A previous rescan panicked."))?;
+
+        let emit = |event: ServerEvent<'_>| self.emit(event);
+
+        let scanned = par_rehash(&repository.root, &stamps, &emit)?;
+        let new_stamps = stamps_of(&scanned);
+
+        // Compared with the metadata, a chmod or a new mtime has to reach the clients too.
+        let current = repository.snapshot();
+        let old_files: HashMap<&str, &HashedFile> = current.files.iter().map(|f| (f.get_path(), f)).collect();
+
+        let mut changed = scanned.iter()
+            .filter(|(f, _)| old_files.get(f.get_path()).is_none_or(|old| *old != f))
+            .count();
+        changed += old_files.keys().filter(|path| !new_stamps.contains_key(**path)).count();
+
+        // Nothing a client can see changed, only the stamps need to be remembered.
+        if changed == 0 {
+            *stamps = new_stamps;
+            return Ok(None);
+        }
+
+        let files: Vec<HashedFile> = scanned.into_iter().map(|(f, _)| f).collect();
+
+        let paths_map = match self.cache {
+            Some(ref path) => Some(update_cache(path, &repository_dir(path, name), base_dir(&repository.root), &files, &emit)?),
+            None => None,
+        };
+
+        let file_count = files.len();
+        let state = ServerState::new(files, paths_map, &repository.root)?;
+
+        match repository.state.write() {
+            Ok(mut current) => *current = Arc::new(state),
+            Err(poisoned) => *poisoned.into_inner() = Arc::new(state),
+        }
+
+        *stamps = new_stamps;
+
+        Ok(Some((file_count, changed)))
+    }
+
+    /// Accepts connections until `shutdown` is cancelled. Open connections then get to finish the request
+    /// they're on, those still busy after the shutdown timeout are cut off. A failed accept is reported and doesn't end it.
+    pub async fn serve(&self, listener: TcpListener, shutdown: CancellationToken) -> io::Result<ServeSummary> {
+        let mut connections = JoinSet::new();
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(error) => {
+                            self.emit(ServerEvent::AcceptFailed { error: &error });
+
+                            // Mostly out of file descriptors or a peer that gave up, both pass once connections close.
+                            tokio::select! {
+                                _ = time::sleep(ACCEPT_BACKOFF) => continue,
+                                _ = shutdown.cancelled() => break,
+                            }
+                        },
+                    };
+
+                    let server = self.clone();
+                    let shutdown = shutdown.clone();
+
+                    connections.spawn(async move {
+                        // Already reported through the Failed event.
+                        let _ = server.handle(stream, Some(peer), shutdown).await;
+                    });
+                },
+                Some(_) = connections.join_next(), if !connections.is_empty() => (),
+                _ = shutdown.cancelled() => break,
+            }
+        }
+
+        drop(listener);
+
+        let drained = time::timeout(self.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        }).await;
+
+        let cut_off = connections.len();
+
+        if drained.is_err() {
+            connections.shutdown().await;
+        }
+
+        Ok(ServeSummary {
+            connections: self.stats.connections.load(Ordering::Relaxed),
+            failed: self.stats.failed.load(Ordering::Relaxed),
+            files_sent: self.stats.files_sent.load(Ordering::Relaxed),
+            bytes_sent: self.stats.bytes_sent.load(Ordering::Relaxed),
+            cut_off,
+        })
+    }
+
+    /// Serves a single connection the caller accepted itself, wrapped in TLS if the server has it configured.
+    pub async fn serve_stream<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S, shutdown: CancellationToken) -> io::Result<()> {
+        self.handle(stream, None, shutdown).await
+    }
+
+    fn emit(&self, event: ServerEvent<'_>) {
+        if let Some(ref callback) = self.events {
+            callback(&event);
+        }
+    }
+
+    async fn handle<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S, peer: Option<SocketAddr>, shutdown: CancellationToken) -> io::Result<()> {
+        let id = self.stats.connections.fetch_add(1, Ordering::Relaxed);
+
+        self.emit(ServerEvent::Connected { id, peer });
 
-        match request.get_type() {
-            RequestType::GetHashes => {
-                stream.write_all(&hashes).await?;
+        let result = match self.tls {
+            Some(ref acceptor) => match acceptor.accept(stream).await {
+                Ok(tls_stream) => self.handle_connection(id, tls_stream, &shutdown).await,
+                Err(err) => Err(err),
             },
+            None => self.handle_connection(id, stream, &shutdown).await,
+        };
 
-            RequestType::GetFiles => {
-                let mut files = vec![0u8; *request.get_body_size()];
-                stream.read_exact(&mut files).await?;
-                let files = match str::from_utf8(&files) {
-                    Ok(f) => f,
-                    Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Couldn't convert body to string.")),
-                };
+        match result {
+            Ok(()) => self.emit(ServerEvent::Closed { id }),
+            Err(ref error) => {
+                self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                self.emit(ServerEvent::Failed { id, error });
+            },
+        }
+
+        result
+    }
 
-                let mut buffer = vec![0u8; 32768];
-                let mut compression_buffer = Vec::new();
+    async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(&self, id: u64, stream: S, shutdown: &CancellationToken) -> io::Result<()> {
+        let mut connection = Connection::new(stream);
 
-                for file in files.lines() {
-                    let file_name_len = file.len() as u32;
+        // The whole connection works on the manifests that were current when it came in.
+        let snapshots: HashMap<String, Arc<ServerState>> = self.repositories.iter()
+            .map(|(name, repository)| (name.clone(), repository.snapshot()))
+            .collect();
 
-                    let header = create_header(RequestVersion::ZEROpOne, RequestType::GiveFiles, file_name_len, 0);
+        let result = self.serve_requests(id, &mut connection, &snapshots, shutdown).await;
 
-                    stream.write_all(&header).await?;
-                    stream.write_all(file.as_bytes()).await?;
+        if let Err(ref err) = result && let Some(code) = ErrorCode::from_io_error(err) {
+            // Best effort, the connection gets closed afterwards anyway.
+            let _ = connection.send_error(&ErrorResponse::new(code, "", &err.to_string())).await;
+        }
 
-                    if let Some(paths_map) = paths_map.as_ref() {
-                        let path = match paths_map.get(file) {
-                            Some(p) => p,
-                            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid file requested by client.")),
-                        };
+        result
+    }
+
+    async fn serve_requests<S: AsyncRead + AsyncWrite + Unpin>(&self, id: u64, connection: &mut Connection<S>, snapshots: &HashMap<String, Arc<ServerState>>, shutdown: &CancellationToken) -> io::Result<()> {
+        // GET-HASHES picks a repository and release, GET-SIGNATURE and GET-FILES name their repository and stay on
+        // that release if it's the same one, resumes and deltas always go to the last one picked.
+        let mut selected: Selected<'_> = find_repository(snapshots, "").map(|(key, state)| (key, LATEST_TAG.to_string(), Arc::clone(state)));
+        let mut stream_manifest = false;
+        let mut format = ManifestFormat::Text;
+
+        loop {
+            let request = tokio::select! {
+                request = connection.receive() => request?,
+                _ = shutdown.cancelled() => break,
+            };
+
+            match request.get_type() {
+                RequestType::Hello => {
+                    let client_hello = Hello::decode(request.get_body())?;
+
+                    let session = self.hello.negotiate(&client_hello)?;
+
+                    // The answer still goes out with the version the HELLO came in, everything after uses the negotiated one.
+                    connection.send(RequestType::Hello, b"", &session.encode()).await?;
 
-                        let mut file_handle = fs::File::open(path).await?;
+                    if let Some(version) = session.highest_version() {
+                        connection.set_version(version);
+                        self.emit(ServerEvent::Negotiated { id, version });
+                    }
+                    connection.set_checksum(session.has_capability(&Capability::Checksum));
+                    stream_manifest = session.has_capability(&Capability::ManifestStream);
+                    format = ManifestFormat::negotiated(&session);
+                },
+
+                RequestType::GetHashes => {
+                    let name = request.get_file_name_str()?;
+                    let tag = str::from_utf8(request.get_body())
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Couldn't convert tag to string."))?;
+
+                    let Some((key, live)) = find_repository(snapshots, name) else {
+                        self.send_error(id, connection, ErrorCode::UnknownRepository, name, "No repository with that name.").await?;
+                        continue;
+                    };
 
-                        loop {
-                            let n = file_handle.read(&mut buffer).await?;
-                            if n == 0 { break; }
+                    let state = if tag.is_empty() || tag == LATEST_TAG {
+                        Arc::clone(live)
+                    } else {
+                        let server = self.clone();
+                        let (repository, release) = (key.clone(), tag.to_string());
 
-                            let header = create_header(RequestVersion::ZEROpOne, RequestType::Chunk, 0, n as u32);
-                            stream.write_all(&header).await?;
-                            stream.write_all(&buffer[..n]).await?;
+                        match task::spawn_blocking(move || server.load_release(&repository, &release)).await? {
+                            Ok(state) => state,
+                            Err(err) if matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::InvalidInput) => {
+                                self.send_error(id, connection, ErrorCode::UnknownRelease, tag, "No release with that tag.").await?;
+                                continue;
+                            },
+                            Err(err) => {
+                                self.send_error(id, connection, ErrorCode::Io, tag, &format!("Couldn't load the release: {err}")).await?;
+                                continue;
+                            },
                         }
+                    };
 
-                        let end_header = create_header(RequestVersion::ZEROpOne, RequestType::EndFile, 0, 0);
-                        stream.write_all(&end_header).await?;
+                    let manifest = state.manifest(format)?;
 
+                    if stream_manifest {
+                        send_manifest(connection, manifest, format).await?;
                     } else {
-                        let mut file_handle = fs::File::open(file).await?;
-                        let mut encoder = DeflateEncoder::new(&mut compression_buffer, Compression::fast());
-
-                        loop {
-                            let n = file_handle.read(&mut buffer).await?;
-                            if n == 0 { break; }
-                            
-                            encoder.write_all(&buffer[..n])?;
-                            let compressed_data = encoder.get_mut();
-
-                            if !compressed_data.is_empty() {
-                                let chunk_header = create_header(RequestVersion::ZEROpOne, RequestType::Chunk, 0, compressed_data.len() as u32);
-                                stream.write_all(&chunk_header).await?;
-                                stream.write_all(compressed_data).await?;
-
-                                compressed_data.clear();
-                            }
+                        connection.send(RequestType::GiveHashes, b"", &manifest.manifest).await?;
+                    }
+                    selected = Some((key, tag.to_string(), state));
+                },
+
+                RequestType::GetSignature => {
+                    let name = request.get_file_name_str()?;
+
+                    let Some((state, tag)) = requested_state(snapshots, &selected, name) else {
+                        self.send_error(id, connection, ErrorCode::UnknownRepository, name, "No repository with that name.").await?;
+                        continue;
+                    };
+
+                    // Empty if the manifest isn't signed.
+                    let signature = match self.signer {
+                        Some(ref signer) => signer.sign(&signed_payload(name, tag, &state.manifest(format)?.digest)).to_vec(),
+                        None => Vec::new(),
+                    };
+
+                    connection.send(RequestType::GiveSignature, b"", &signature).await?;
+                },
+
+                RequestType::GetFiles => {
+                    let name = request.get_file_name_str()?;
+
+                    let Some((state, _)) = requested_state(snapshots, &selected, name) else {
+                        self.send_error(id, connection, ErrorCode::UnknownRepository, name, "No repository with that name.").await?;
+                        continue;
+                    };
+
+                    let files = if format != ManifestFormat::Text {
+                        decode_names(request.get_body())?
+                    } else {
+                        match str::from_utf8(request.get_body()) {
+                            Ok(f) => f.lines().collect(),
+                            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Couldn't convert body to string.")),
+                        }
+                    };
+
+                    let mut buffer = vec![0u8; 32768];
+                    let mut compression_buffer = Vec::new();
+
+                    for file in files {
+                        if !state.advertised.contains(file) {
+                            self.send_error(id, connection, ErrorCode::UnknownFile, file, "File isn't part of the served file list.").await?;
+                            continue;
                         }
 
-                        let final_compressed_data = encoder.finish()?;
-                        if !final_compressed_data.is_empty() {
-                            let chunk_header = create_header(RequestVersion::ZEROpOne, RequestType::Chunk, 0, final_compressed_data.len() as u32);
-                            stream.write_all(&chunk_header).await?;
-                            stream.write_all(final_compressed_data).await?;
+                        let source = match state.paths_map {
+                            Some(_) => state.cached_path(file)?,
+                            None => join_relative(&state.root, file)?,
+                        };
+
+                        let file_handle = match fs::File::open(&source).await {
+                            Ok(f) => f,
+                            Err(err) => {
+                                self.send_error(id, connection, ErrorCode::Io, file, &format!("Couldn't open file: {err}")).await?;
+                                continue;
+                            },
+                        };
+
+                        connection.send(RequestType::GiveFiles, file.as_bytes(), b"").await?;
+
+                        if state.paths_map.is_some() {
+                            self.send_cached_file(id, connection, file, file_handle, &mut buffer).await?;
+                        } else {
+                            self.send_compressed_file(id, connection, file, file_handle, &mut buffer, &mut compression_buffer).await?;
                         }
+                    }
+                },
+
+                RequestType::GetFilesFrom => {
+                    let file_name = request.get_file_name_str()?;
 
-                        let end_header = create_header(RequestVersion::ZEROpOne, RequestType::EndFile, 0, 0);
-                        stream.write_all(&end_header).await?;
+                    let offset: [u8; 8] = request.get_body().try_into()
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Resume request doesn't contain a valid offset."))?;
+                    let offset = u64::from_be_bytes(offset);
 
-                        compression_buffer.clear();
+                    let Some((_, _, ref state)) = selected else {
+                        self.send_error(id, connection, ErrorCode::UnknownRepository, file_name, "Ask for the hashes of a repository first.").await?;
+                        continue;
+                    };
+
+                    if !state.advertised.contains(file_name) {
+                        self.send_error(id, connection, ErrorCode::UnknownFile, file_name, "File isn't part of the served file list.").await?;
+                        continue;
+                    }
+
+                    // The cached files are a single deflate stream, so resumed transfers are compressed from the origin file.
+                    let file_handle = match open_origin_at(state, file_name, offset).await {
+                        Ok(f) => f,
+                        Err(err) => {
+                            self.send_error(id, connection, ErrorCode::Io, file_name, &format!("Couldn't open file: {err}")).await?;
+                            continue;
+                        },
+                    };
+
+                    connection.send(RequestType::GiveFiles, file_name.as_bytes(), b"").await?;
+
+                    let mut buffer = vec![0u8; 32768];
+                    let mut compression_buffer = Vec::new();
+                    self.send_compressed_file(id, connection, file_name, file_handle, &mut buffer, &mut compression_buffer).await?;
+                },
+
+                RequestType::GetDelta => {
+                    let file_name = request.get_file_name_str()?;
+                    let (block_size, signatures) = decode_signatures(request.get_body())?;
+
+                    let Some((_, _, ref state)) = selected else {
+                        self.send_error(id, connection, ErrorCode::UnknownRepository, file_name, "Ask for the hashes of a repository first.").await?;
+                        continue;
+                    };
+
+                    if !state.advertised.contains(file_name) {
+                        self.send_error(id, connection, ErrorCode::UnknownFile, file_name, "File isn't part of the served file list.").await?;
+                        continue;
                     }
+
+                    let file_handle = match open_origin(state, file_name).await {
+                        Ok(f) => f,
+                        Err(err) => {
+                            self.send_error(id, connection, ErrorCode::Io, file_name, &format!("Couldn't open file: {err}")).await?;
+                            continue;
+                        },
+                    };
+
+                    self.send_delta(id, connection, file_name, file_handle, block_size, signatures).await?;
+                },
+
+                RequestType::Disconnect => break,
+
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Got an invalid request type.")),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_error<S: AsyncRead + AsyncWrite + Unpin>(&self, id: u64, connection: &mut Connection<S>, code: ErrorCode, file_name: &str, message: &str) -> io::Result<()> {
+        let error = ErrorResponse::new(code, file_name, message);
+        connection.send_error(&error).await?;
+
+        self.emit(ServerEvent::ErrorSent { id, error: &error });
+        Ok(())
+    }
+
+    async fn end_file<S: AsyncRead + AsyncWrite + Unpin>(&self, id: u64, connection: &mut Connection<S>, file_name: &str, bytes: u64) -> io::Result<()> {
+        connection.send(RequestType::EndFile, b"", b"").await?;
+
+        self.stats.files_sent.fetch_add(1, Ordering::Relaxed);
+        self.stats.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        self.emit(ServerEvent::FileSent { id, path: file_name, bytes });
+        Ok(())
+    }
+
+    // Read errors in the middle of a file are reported with an ERROR in place of the END-FILE, the connection stays usable.
+    async fn send_cached_file<S: AsyncRead + AsyncWrite + Unpin>(&self, id: u64, connection: &mut Connection<S>, file_name: &str, mut file_handle: fs::File, buffer: &mut [u8]) -> io::Result<()> {
+        let mut bytes = 0;
+
+        loop {
+            let n = match file_handle.read(buffer).await {
+                Ok(n) => n,
+                Err(err) => return self.send_error(id, connection, ErrorCode::Io, file_name, &format!("Couldn't read file: {err}")).await,
+            };
+            if n == 0 { break; }
+
+            connection.send(RequestType::Chunk, b"", &buffer[..n]).await?;
+            bytes += n as u64;
+        }
+
+        self.end_file(id, connection, file_name, bytes).await
+    }
+
+    async fn send_compressed_file<S: AsyncRead + AsyncWrite + Unpin, R: AsyncRead + Unpin>(&self, id: u64, connection: &mut Connection<S>, file_name: &str, mut file_handle: R, buffer: &mut [u8], compression_buffer: &mut Vec<u8>) -> io::Result<()> {
+        compression_buffer.clear();
+        let mut encoder = DeflateEncoder::new(&mut *compression_buffer, Compression::fast());
+        let mut bytes = 0;
+
+        loop {
+            let n = match file_handle.read(buffer).await {
+                Ok(n) => n,
+                Err(err) => return self.send_error(id, connection, ErrorCode::Io, file_name, &format!("Couldn't read file: {err}")).await,
+            };
+            if n == 0 { break; }
+
+            encoder.write_all(&buffer[..n])?;
+            let compressed_data = encoder.get_mut();
+
+            if !compressed_data.is_empty() {
+                connection.send(RequestType::Chunk, b"", compressed_data).await?;
+                bytes += compressed_data.len() as u64;
+
+                compressed_data.clear();
+            }
+        }
+
+        let final_compressed_data = encoder.finish()?;
+        if !final_compressed_data.is_empty() {
+            connection.send(RequestType::Chunk, b"", final_compressed_data).await?;
+            bytes += final_compressed_data.len() as u64;
+        }
+
+        self.end_file(id, connection, file_name, bytes).await?;
+
+        compression_buffer.clear();
+
+        Ok(())
+    }
+
+    async fn send_delta<S: AsyncRead + AsyncWrite + Unpin>(&self, id: u64, connection: &mut Connection<S>, file_name: &str, mut file_handle: Box<dyn Read + Send>, block_size: u32, signatures: Vec<BlockSignature>) -> io::Result<()> {
+        connection.send(RequestType::GiveDelta, file_name.as_bytes(), b"").await?;
+
+        let (tx, mut rx) = mpsc::channel::<DeltaOp>(100);
+
+        let delta_handle = task::spawn_blocking(move || {
+            compute_delta(&mut file_handle, block_size, &signatures, |op| {
+                tx.blocking_send(op).map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err.to_string()))
+            })
+        });
+
+        let mut bytes = 0;
+
+        while let Some(op) = rx.recv().await {
+            match op {
+                DeltaOp::Copy(index) => {
+                    connection.send(RequestType::DeltaCopy, b"", &index.to_be_bytes()).await?;
+                    bytes += 8;
+                },
+                DeltaOp::Literal(data) => {
+                    connection.send(RequestType::Chunk, b"", &data).await?;
+                    bytes += data.len() as u64;
+                },
+            }
+        }
+
+        if let Err(err) = delta_handle.await? {
+            return self.send_error(id, connection, ErrorCode::Io, file_name, &format!("Couldn't read file: {err}")).await;
+        }
+
+        self.end_file(id, connection, file_name, bytes).await
+    }
+}
+
+/// GIVE-HASHES with the entry count, then the manifest in CHUNKs of whole entries and an END-FILE.
+/// The client never has to take the manifest in one piece, whatever its size.
+async fn send_manifest<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<S>, manifest: &EncodedManifest, format: ManifestFormat) -> io::Result<()> {
+    connection.send(RequestType::GiveHashes, b"", &(manifest.count as u64).to_be_bytes()).await?;
+
+    let mut rest = manifest.manifest.as_slice();
+
+    while !rest.is_empty() {
+        // Cut after the last entry that fits, an entry longer than a chunk goes out on its own.
+        let end = if format != ManifestFormat::Text {
+            let metadata = format == ManifestFormat::Metadata;
+            let mut end = manifest_entry_len(rest, metadata)?;
+
+            while end < rest.len() {
+                let next = manifest_entry_len(&rest[end..], metadata)?;
+                if end + next > MANIFEST_CHUNK_SIZE {
+                    break;
                 }
-            },
+                end += next;
+            }
+
+            end
+        } else {
+            let limit = rest.len().min(MANIFEST_CHUNK_SIZE);
+
+            match rest[..limit].iter().rposition(|b| *b == b'\n') {
+                Some(pos) if limit < rest.len() => pos + 1,
+                _ if limit == rest.len() => limit,
+                _ => rest.iter().position(|b| *b == b'\n').map_or(rest.len(), |pos| pos + 1),
+            }
+        };
 
-            RequestType::Disconnect => break,
-            
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Got an invalid request type.")),
+        let (chunk, remaining) = rest.split_at(end);
+        connection.send(RequestType::Chunk, b"", chunk).await?;
+        rest = remaining;
+    }
+
+    connection.send(RequestType::EndFile, b"", b"").await
+}
+
+/// The uncompressed content of `file_name`, release snapshots keep it in their objects.
+async fn open_origin(state: &ServerState, file_name: &str) -> io::Result<Box<dyn Read + Send>> {
+    if state.frozen {
+        let object = fs::File::open(state.cached_path(file_name)?).await?.into_std().await;
+        return Ok(Box::new(DeflateDecoder::new(object)));
+    }
+
+    let path = join_relative(&state.root, file_name)?;
+    Ok(Box::new(fs::File::open(&path).await?.into_std().await))
+}
+
+async fn open_origin_at(state: &ServerState, file_name: &str, offset: u64) -> io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+    if state.frozen {
+        return Ok(Box::new(open_object_at(&state.cached_path(file_name)?, offset).await?));
+    }
+
+    let path = join_relative(&state.root, file_name)?;
+    Ok(Box::new(open_at(&path, offset).await?))
+}
+
+/// Streams the decompressed content of a release object from `offset` on, the decompression runs on a blocking thread.
+/// A failure there ends the stream early, which the client's hash check catches.
+async fn open_object_at(path: &Path, offset: u64) -> io::Result<DuplexStream> {
+    let object = fs::File::open(path).await?.into_std().await;
+    let (reader, writer) = tokio::io::duplex(65536);
+
+    task::spawn_blocking(move || -> io::Result<()> {
+        let mut decoder = DeflateDecoder::new(object);
+
+        io::copy(&mut (&mut decoder).take(offset), &mut io::sink())?;
+        io::copy(&mut decoder, &mut SyncIoBridge::new(writer))?;
+
+        Ok(())
+    });
+
+    Ok(reader)
+}
+
+async fn open_at(path: &Path, offset: u64) -> io::Result<fs::File> {
+    let mut file_handle = fs::File::open(path).await?;
+
+    if offset > 0 {
+        file_handle.seek(SeekFrom::Start(offset)).await?;
+    }
+
+    Ok(file_handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, hash: &str, kind: FileKind) -> HashedFile {
+        HashedFile::new(path, hash).with_metadata(FileMetadata::new(kind, 0, None, None))
+    }
+
+    fn state() -> ServerState {
+        let files = vec![
+            entry("sub", "", FileKind::Directory),
+            entry("sub/f.txt", "aaaa", FileKind::Regular),
+            entry("bin", "", FileKind::Directory),
+            entry("bin/link", "", FileKind::Symlink("../sub/f.txt".to_string())),
+            entry("bin/dir", "", FileKind::Symlink("../sub".to_string())),
+            entry("bin/dangling", "", FileKind::Symlink("gone".to_string())),
+        ];
+
+        let paths_map = HashMap::from([("sub/f.txt".to_string(), "objects/aa/aa".to_string())]);
+
+        ServerState::new(files, Some(paths_map), Path::new("root")).unwrap()
+    }
+
+    #[test]
+    fn link_destination_resolves_inside_the_root() {
+        assert_eq!(link_destination("b/link", "../a/f").as_deref(), Some("a/f"));
+        assert_eq!(link_destination("a/link", "./f").as_deref(), Some("a/f"));
+        assert_eq!(link_destination("a/b/link", "../../c").as_deref(), Some("c"));
+        assert_eq!(link_destination("link", "../a/f"), None);
+    }
+
+    #[test]
+    fn older_manifests_list_linked_files_as_copies() {
+        let state = state();
+
+        let text = state.manifest(ManifestFormat::Text).unwrap();
+        assert_eq!(text.manifest, b"sub/f.txt aaaa\nbin/link aaaa\nbin/dir/f.txt aaaa\n");
+        assert_eq!(text.count, 3);
+
+        assert!(state.advertised.contains("bin/link"));
+        assert!(state.advertised.contains("bin/dir/f.txt"));
+        assert!(!state.advertised.contains("bin/dir"));
+        assert_eq!(state.cached_path("bin/link").unwrap(), PathBuf::from("objects/aa/aa"));
+        assert_eq!(state.cached_path("bin/dir/f.txt").unwrap(), PathBuf::from("objects/aa/aa"));
+    }
+
+    #[test]
+    fn linked_directories_copy_files_and_file_links() {
+        let files = [
+            entry("d", "", FileKind::Directory),
+            entry("d/f", "aaaa", FileKind::Regular),
+            entry("d/g", "", FileKind::Symlink("f".to_string())),
+            entry("d/e", "", FileKind::Symlink("..".to_string())),
+            entry("d-x", "bbbb", FileKind::Regular),
+            entry("l", "", FileKind::Symlink("d".to_string())),
+        ];
+
+        let linked = linked_files(&files);
+
+        assert_eq!(linked.into_iter().collect::<Vec<_>>(), [
+            ("d/g".to_string(), "d/f".to_string()),
+            ("l/f".to_string(), "d/f".to_string()),
+            ("l/g".to_string(), "d/f".to_string()),
+        ]);
+    }
+
+    fn test_server(root: &Path, cache: Option<&Path>) -> RepairServer {
+        RepairServer::new(ServerConfig {
+            repositories: vec![RepositoryConfig { name: String::new(), root: root.to_path_buf() }],
+            cache: cache.map(Path::to_path_buf),
+            verify_cache: false,
+            tls: None,
+            signer: None,
+            events: None,
+        }).unwrap()
+    }
+
+    /// A connection to `server` that talks 0.1 without a HELLO, like the clients from before the handshake.
+    fn connect(server: RepairServer) -> (Connection<DuplexStream>, task::JoinHandle<io::Result<()>>) {
+        let (client, stream) = tokio::io::duplex(65536);
+        let serving = tokio::spawn(async move { server.serve_stream(stream, CancellationToken::new()).await });
+
+        (Connection::new(client), serving)
+    }
+
+    /// Reads the GIVE-FILES answer for `name` and inflates its chunks.
+    async fn receive_file(connection: &mut Connection<DuplexStream>, name: &str) -> Vec<u8> {
+        let response = connection.receive().await.unwrap().into_result().unwrap();
+        assert_eq!(response.get_type(), &RequestType::GiveFiles);
+        assert_eq!(response.get_file_name(), name.as_bytes());
+
+        let mut compressed = Vec::new();
+        loop {
+            let message = connection.receive().await.unwrap().into_result().unwrap();
+
+            match message.get_type() {
+                RequestType::Chunk => compressed.extend_from_slice(message.get_body()),
+                RequestType::EndFile => break,
+                other => panic!("unexpected {other}"),
+            }
         }
+
+        let mut content = Vec::new();
+        DeflateDecoder::new(compressed.as_slice()).read_to_end(&mut content).unwrap();
+        content
     }
 
-    Ok(())
+    /// Sends `files` through `send_manifest` and returns the GIVE-HASHES count and the CHUNK bodies.
+    async fn manifest_chunks(files: &[HashedFile], format: ManifestFormat) -> (EncodedManifest, u64, Vec<Vec<u8>>) {
+        let manifest = encode_manifest(files, format).unwrap();
+        let (client, server) = tokio::io::duplex(65536);
+        let (mut client, mut server) = (Connection::new(client), Connection::new(server));
+
+        let receiving = async {
+            let response = client.receive().await.unwrap().into_result().unwrap();
+            assert_eq!(response.get_type(), &RequestType::GiveHashes);
+            let count = u64::from_be_bytes(response.get_body().try_into().unwrap());
+
+            let mut chunks = Vec::new();
+            loop {
+                let message = client.receive().await.unwrap().into_result().unwrap();
+
+                match message.get_type() {
+                    RequestType::Chunk => chunks.push(message.get_body().to_vec()),
+                    RequestType::EndFile => break,
+                    other => panic!("unexpected {other}"),
+                }
+            }
+
+            (count, chunks)
+        };
+
+        let (sent, (count, chunks)) = tokio::join!(send_manifest(&mut server, &manifest, format), receiving);
+        sent.unwrap();
+
+        (manifest, count, chunks)
+    }
+
+    fn text_entries(chunk: &[u8], files: &mut Vec<HashedFile>) {
+        assert_eq!(chunk.last(), Some(&b'\n'));
+
+        for line in str::from_utf8(chunk).unwrap().lines() {
+            let (path, hash) = line.rsplit_once(' ').unwrap();
+            files.push(HashedFile::new(path, hash));
+        }
+    }
+
+    #[tokio::test]
+    async fn manifest_chunks_hold_whole_entries() {
+        let hash = "0123456789abcdef".repeat(4);
+        let mut files = Vec::new();
+
+        // About 1000 bytes an entry, so the 64 KiB boundaries fall inside entries.
+        for i in 0..200 {
+            files.push(HashedFile::new(&format!("dir/{i:04}-{}", "x".repeat(900 + i % 7)), &hash)
+                .with_metadata(FileMetadata::new(FileKind::Regular, i as u64, Some(0o644), Some(1_700_000_000 + i as u64))));
+        }
+        // Bigger than a chunk on its own.
+        files.insert(100, HashedFile::new(&"y".repeat(MANIFEST_CHUNK_SIZE + 100), &hash)
+            .with_metadata(FileMetadata::new(FileKind::Regular, 1, None, None)));
+
+        for format in [ManifestFormat::Text, ManifestFormat::LengthPrefixed, ManifestFormat::Metadata] {
+            let (manifest, count, chunks) = manifest_chunks(&files, format).await;
+            assert_eq!(count, files.len() as u64);
+            assert!(chunks.len() > 3, "{format:?}");
+            assert_eq!(chunks.concat(), manifest.manifest, "{format:?}");
+
+            let mut received = Vec::new();
+
+            for chunk in &chunks {
+                let before = received.len();
+
+                match format {
+                    ManifestFormat::Text => text_entries(chunk, &mut received),
+                    _ => decode_manifest(chunk, format == ManifestFormat::Metadata, &mut received).unwrap(),
+                }
+
+                assert!(chunk.len() <= MANIFEST_CHUNK_SIZE || received.len() == before + 1, "{format:?}");
+            }
+
+            let expected: Vec<(&str, &str)> = files.iter().map(|f| (f.get_path(), f.get_hash())).collect();
+            assert_eq!(received.iter().map(|f| (f.get_path(), f.get_hash())).collect::<Vec<_>>(), expected, "{format:?}");
+
+            if format == ManifestFormat::Metadata {
+                assert_eq!(received, files);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_manifest_sends_no_chunks() {
+        for format in [ManifestFormat::Text, ManifestFormat::LengthPrefixed, ManifestFormat::Metadata] {
+            let (_, count, chunks) = manifest_chunks(&[], format).await;
+            assert_eq!(count, 0);
+            assert!(chunks.is_empty());
+        }
+    }
+
+    /// Old clients only know text manifests.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn old_client_gets_files_through_a_symlinked_directory() {
+        let dir = crate::testing::TempDir::new();
+        std::fs::create_dir(dir.path().join("data")).unwrap();
+        std::fs::write(dir.path().join("data/f.txt"), b"linked content").unwrap();
+        std::os::unix::fs::symlink("data", dir.path().join("link")).unwrap();
+
+        let (mut connection, serving) = connect(test_server(dir.path(), None));
+        connection.send(RequestType::GetHashes, b"", b"").await.unwrap();
+
+        let manifest = connection.receive().await.unwrap().into_result().unwrap();
+        let manifest = String::from_utf8(manifest.into_body()).unwrap();
+
+        let mut names: Vec<&str> = manifest.lines().map(|line| line.split_once(' ').unwrap().0).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["data/f.txt", "link/f.txt"]);
+
+        connection.send(RequestType::GetFiles, b"", b"link/f.txt\n").await.unwrap();
+        assert_eq!(receive_file(&mut connection, "link/f.txt").await, b"linked content");
+
+        connection.send(RequestType::Disconnect, b"", b"").await.unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_from_a_release_reads_its_snapshot() {
+        let dir = crate::testing::TempDir::new();
+        let root = dir.path().join("root");
+        let cache = dir.path().join("cache");
+
+        let released: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("f.txt"), &released).unwrap();
+
+        let server = test_server(&root, Some(&cache));
+        server.release("", "v1").unwrap();
+
+        // The live file moves on, a resume must not mix it into the release.
+        std::fs::write(root.join("f.txt"), vec![b'x'; released.len()]).unwrap();
+
+        let (mut connection, serving) = connect(server);
+        connection.send(RequestType::GetHashes, b"", b"v1").await.unwrap();
+        connection.receive().await.unwrap().into_result().unwrap();
+
+        connection.send(RequestType::GetFilesFrom, b"f.txt", &40_000u64.to_be_bytes()).await.unwrap();
+        assert_eq!(receive_file(&mut connection, "f.txt").await, &released[40_000..]);
+
+        connection.send(RequestType::Disconnect, b"", b"").await.unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn metadata_manifest_keeps_the_links() {
+        let state = state();
+
+        let mut files = Vec::new();
+        decode_manifest(&state.manifest(ManifestFormat::Metadata).unwrap().manifest, true, &mut files).unwrap();
+
+        assert_eq!(files.len(), 6);
+        assert_eq!(files[3].get_metadata().unwrap().get_kind(), &FileKind::Symlink("../sub/f.txt".to_string()));
+    }
 }
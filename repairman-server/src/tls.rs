@@ -0,0 +1,30 @@
+use std::{io, path::Path, sync::Arc};
+
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to read certificates from {:?}: {}", cert_path, e)))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid certificate in {:?}: {}", cert_path, e)))?;
+
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No certificates found in {:?}", cert_path)));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to read private key from {:?}: {}", key_path, e)))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}